@@ -0,0 +1,73 @@
+use btclib::sha256::Hash;
+use btclib::types::{BlockHeader, HeaderHasher};
+use btclib::util::MerkleRoot;
+use btclib::U256;
+use chrono::Utc;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::hint::black_box;
+
+const STEPS: usize = 10_000;
+
+fn header() -> BlockHeader {
+    BlockHeader::new(
+        Utc::now(),
+        0,
+        Hash::zero(),
+        MerkleRoot::calculate(&[btclib::types::Transaction::coinbase(0, vec![])]),
+        // nothing matches, every nonce gets hashed
+        U256::zero(),
+    )
+}
+
+/// Hashing one nonce: CBOR as mining used to, the consensus
+/// encoding, and the midstate path `BlockHeader::mine` now uses
+fn header_hash(c: &mut Criterion) {
+    let mut group = c.benchmark_group("header_hash");
+    let mut header = header();
+    group.bench_function("cbor", |b| {
+        b.iter(|| {
+            header.nonce += 1;
+            black_box(Hash::hash(&header))
+        })
+    });
+    group.bench_function("encoded", |b| {
+        b.iter(|| {
+            header.nonce += 1;
+            black_box(header.hash())
+        })
+    });
+    let mut hasher = HeaderHasher::new(&header);
+    let mut nonce = 0;
+    group.bench_function("midstate", |b| {
+        b.iter(|| {
+            nonce += 1;
+            black_box(hasher.matches_target(nonce))
+        })
+    });
+    group.finish();
+}
+
+/// A batch of nonces as `mine_block` and the miner run it
+fn mine(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mine");
+    group.throughput(Throughput::Elements(STEPS as u64));
+    let mut cbor_header = header();
+    group.bench_function("cbor", |b| {
+        b.iter(|| {
+            for _ in 0..STEPS {
+                cbor_header.nonce += 1;
+                if Hash::hash(&cbor_header).matches_target(cbor_header.target) {
+                    break;
+                }
+            }
+        })
+    });
+    let mut midstate_header = header();
+    group.bench_function("midstate", |b| {
+        b.iter(|| black_box(midstate_header.mine(STEPS)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, header_hash, mine);
+criterion_main!(benches);
@@ -4,7 +4,6 @@ use btclib::types::{Block, BlockHeader, Transaction, TransactionOutput};
 use btclib::util::{MerkleRoot, Saveable};
 
 use chrono::Utc;
-use uuid::Uuid;
 
 use std::env;
 use std::process::exit;
@@ -19,10 +18,9 @@ fn main() {
 
     let private_key = PrivateKey::new_key();
 
-    let transactions = vec![Transaction::new(
-        vec![],
+    let transactions = vec![Transaction::coinbase(
+        0,
         vec![TransactionOutput {
-            unique_id: Uuid::new_v4(),
             value: btclib::INITIAL_REWARD * 10u64.pow(8),
             pubkey: private_key.public_key(),
         }],
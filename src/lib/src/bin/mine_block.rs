@@ -1,31 +1,42 @@
+use std::env;
 use std::process::exit;
-use std::{env, usize};
 
+use btclib::mining::{Cancel, Miner};
 use btclib::types::Block;
 use btclib::util::Saveable;
 
 fn main() {
-    let (path, steps) = if let (Some(_1), Some(_2)) = (env::args().nth(1), env::args().nth(2)) {
-        (_1, _2)
+    let path = if let Some(path) = env::args().nth(1) {
+        path
     } else {
-        eprintln!("Usage: miner <block-file> <steps>");
+        eprintln!("Usage: mine_block <block-file> [threads]");
         exit(1);
     };
-    let steps: usize = if let Ok(s @ 1..usize::MAX) = steps.parse() {
-        s
-    } else {
-        eprintln!("<steps> should be a positive integer");
-        exit(1);
+    let miner = match env::args().nth(2).map(|threads| threads.parse()) {
+        None => Miner::with_available_parallelism(),
+        Some(Ok(threads @ 1..)) => Miner::new(threads),
+        Some(_) => {
+            eprintln!("[threads] should be a positive integer");
+            exit(1);
+        }
     };
 
     let og_block = Block::load_from_file(path).expect("failed to load block");
     let mut block = og_block.clone();
-    while !block.header.mine(steps) {
-        println!("mining")
-    }
+    println!("mining on {} threads", miner.threads());
+    let mined = miner.mine(block.header.clone(), &Cancel::new(), |hashrate| {
+        println!("mining, {:.0} H/s", hashrate)
+    });
+    block.header = mined.header.clone().expect("mining was not cancelled");
     println!("original: {:#?}", og_block);
     println!("hash: {}", og_block.header.hash());
     // print mined block and its hash
     println!("final: {:#?}", block);
     println!("hash: {}", block.header.hash());
+    println!(
+        "{} hashes in {:.2?}, {:.0} H/s",
+        mined.hashes,
+        mined.elapsed,
+        mined.hashrate()
+    );
 }
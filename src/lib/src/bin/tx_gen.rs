@@ -2,8 +2,6 @@ use btclib::crypto::PrivateKey;
 use btclib::types::{Transaction, TransactionOutput};
 use btclib::util::Saveable;
 
-use uuid::Uuid;
-
 use std::env;
 use std::process::exit;
 
@@ -15,10 +13,9 @@ fn main() {
         exit(1);
     };
     let private_key = PrivateKey::new_key();
-    let transaction = Transaction::new(
-        vec![],
+    let transaction = Transaction::coinbase(
+        0,
         vec![TransactionOutput {
-            unique_id: Uuid::new_v4(),
             value: btclib::INITIAL_REWARD * 10u64.pow(8),
             pubkey: private_key.public_key(),
         }],
@@ -22,7 +22,7 @@ impl PrivateKey {
         PrivateKey(SigningKey::random(&mut rand::thread_rng()))
     }
     pub fn public_key(&self) -> PublicKey {
-        PublicKey(self.0.verifying_key().clone())
+        PublicKey(*self.0.verifying_key())
     }
 }
 impl Saveable for PrivateKey {
@@ -65,17 +65,14 @@ impl Saveable for PublicKey {
 }
 
 impl Signature {
-    pub fn sign_output(output_hash: &Hash, private_key: &PrivateKey) -> Self {
+    pub fn sign(hash: &Hash, private_key: &PrivateKey) -> Self {
         let signing_key = &private_key.0;
-        let signature = signing_key.sign(&output_hash.as_bytes());
+        let signature = signing_key.sign(&hash.as_bytes());
         Signature(signature)
     }
 
-    pub fn verify(&self, output_hash: &Hash, public_key: &PublicKey) -> bool {
-        public_key
-            .0
-            .verify(&output_hash.as_bytes(), &self.0)
-            .is_ok()
+    pub fn verify(&self, hash: &Hash, public_key: &PublicKey) -> bool {
+        public_key.0.verify(&hash.as_bytes(), &self.0).is_ok()
     }
 }
 
@@ -0,0 +1,267 @@
+//! Consensus encoding: the byte layout block and transaction hashes
+//! are computed over. Unlike the CBOR used for storage and the network
+//! it is defined here field by field, so hashes do not depend on serde
+//! or on any library version.
+//!
+//! All integers are little endian, lists are prefixed with their
+//! length as a u32.
+//!
+//! Block header, always `BlockHeader::SIZE` bytes:
+//!
+//! | field           | bytes |
+//! |-----------------|-------|
+//! | version         | 4     |
+//! | prev_block_hash | 32    |
+//! | merkle_root     | 32    |
+//! | timestamp       | 4     |
+//! | target          | 32    |
+//! | nonce           | 8     |
+//!
+//! The nonce comes last so a miner only rewrites the tail of the header.
+//!
+//! Transaction:
+//!
+//! | field           | bytes                                    |
+//! |-----------------|------------------------------------------|
+//! | version         | 4                                        |
+//! | coinbase_height | 1 (0 = none, 1 = some), then 8 if some   |
+//! | inputs          | 4 + 101 per input                        |
+//! | outputs         | 4 + 41 per output                        |
+//!
+//! An input is the spent txid (32), output index (4), sighash (1) and
+//! the signature as r || s (64). An output is the value (8) and the
+//! SEC1 compressed public key (33).
+use crate::crypto::{PublicKey, Signature};
+use crate::sha256::Hash;
+use crate::types::{
+    BlockHeader, OutPoint, SigHash, Transaction, TransactionInput, TransactionOutput,
+};
+use crate::util::MerkleRoot;
+use crate::U256;
+use std::io::{Result as IoResult, Write};
+
+/// Version of the consensus encoding, written first in every
+/// header and transaction
+pub const ENCODING_VERSION: u32 = 1;
+
+pub trait Encode {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()>;
+
+    fn encoded(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.encode(&mut bytes)
+            .expect("BUG: writing to a Vec can not fail");
+        bytes
+    }
+}
+
+impl Encode for u8 {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_all(&[*self])
+    }
+}
+
+impl Encode for u32 {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+}
+
+impl Encode for u64 {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+}
+
+impl Encode for U256 {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        let mut bytes = [0u8; 32];
+        self.to_little_endian(&mut bytes);
+        writer.write_all(&bytes)
+    }
+}
+
+impl Encode for Hash {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_all(&self.as_bytes())
+    }
+}
+
+impl Encode for MerkleRoot {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.0.encode(writer)
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        (self.len() as u32).encode(writer)?;
+        for item in self {
+            item.encode(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        match self {
+            None => 0u8.encode(writer),
+            Some(value) => {
+                1u8.encode(writer)?;
+                value.encode(writer)
+            }
+        }
+    }
+}
+
+impl Encode for PublicKey {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_all(self.0.to_encoded_point(true).as_bytes())
+    }
+}
+
+impl Encode for Signature {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_all(&self.0.to_bytes())
+    }
+}
+
+impl Encode for SigHash {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.0.encode(writer)
+    }
+}
+
+impl Encode for OutPoint {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.txid.encode(writer)?;
+        self.index.encode(writer)
+    }
+}
+
+impl Encode for TransactionInput {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.prev_output.encode(writer)?;
+        self.sighash.encode(writer)?;
+        self.signature.encode(writer)
+    }
+}
+
+impl Encode for TransactionOutput {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.value.encode(writer)?;
+        self.pubkey.encode(writer)
+    }
+}
+
+impl Encode for Transaction {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        ENCODING_VERSION.encode(writer)?;
+        self.coinbase_height.encode(writer)?;
+        self.inputs.encode(writer)?;
+        self.outputs.encode(writer)
+    }
+}
+
+impl Encode for BlockHeader {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        ENCODING_VERSION.encode(writer)?;
+        self.prev_block_hash.encode(writer)?;
+        self.merkle_root.encode(writer)?;
+        self.timestamp.encode(writer)?;
+        self.target.encode(writer)?;
+        self.nonce.encode(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use ecdsa::SigningKey;
+
+    fn key() -> PrivateKey {
+        PrivateKey(SigningKey::from_slice(&[0x11; 32]).unwrap())
+    }
+
+    fn coinbase() -> Transaction {
+        Transaction::coinbase(
+            7,
+            vec![TransactionOutput {
+                value: 5_000_000_000,
+                pubkey: key().public_key(),
+            }],
+        )
+    }
+
+    /// Spends the coinbase output, signed with SigHash::ALL
+    fn spend() -> Transaction {
+        let prev_output = OutPoint::new(coinbase().hash(), 0);
+        let outputs = vec![TransactionOutput {
+            value: 4_999_990_000,
+            pubkey: key().public_key(),
+        }];
+        let hash = Transaction::signature_hash(&[prev_output], &outputs, 0, SigHash::ALL).unwrap();
+        Transaction::new(
+            vec![TransactionInput {
+                prev_output,
+                signature: Signature::sign(&hash, &key()),
+                sighash: SigHash::ALL,
+            }],
+            outputs,
+        )
+    }
+
+    fn header() -> BlockHeader {
+        BlockHeader {
+            timestamp: 1_700_000_000,
+            nonce: 0x0102_0304_0506_0708,
+            prev_block_hash: Hash::hash_bytes(b"parent"),
+            merkle_root: MerkleRoot::calculate(&[coinbase(), spend()]),
+            target: crate::MIN_TARGET,
+        }
+    }
+
+    #[test]
+    fn header_vector() {
+        let encoded = header().encoded();
+        assert_eq!(encoded.len(), BlockHeader::SIZE);
+        assert_eq!(hex::encode(&encoded), "010000000cffd4378058340bf7acabcfde5913ea710ae4d10248bc9f04713b8b962571e4a682b538a6c5cd03b4f314cd0cc451f066907c654c17fb704d67a47e232c0bbb00f15365ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff00000807060504030201");
+        assert_eq!(
+            header().hash().to_string(),
+            "8612083820a5d45b253fda844f556f1de73e07e9dec1da08dd95ab146782bf4"
+        );
+    }
+
+    #[test]
+    fn coinbase_vector() {
+        assert_eq!(coinbase().size(), coinbase().encoded().len());
+        assert_eq!(hex::encode(coinbase().encoded()), "01000000010700000000000000000000000100000000f2052a01000000034f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa");
+        assert_eq!(
+            coinbase().hash().to_string(),
+            "fae93d091ef95b83aedae5eef3f82aad500f1f671bcc8ea5299086c7caa42886"
+        );
+    }
+
+    #[test]
+    fn signed_transaction_vector() {
+        let encoded = spend().encoded();
+        assert_eq!(encoded.len(), 4 + 1 + 4 + 101 + 4 + 41);
+        assert_eq!(spend().size(), encoded.len());
+        assert_eq!(hex::encode(&encoded), "0100000000010000008628a4cac7869029a58ecc1b671f0f50ad2af8f3eee5daae835bf91e093de9fa0000000001ae4aa63a6b8d3cef1c064d90d286898bd7da7b64fd9ba08e42c3ee450b0b5dd62fd7d4164f0e12f27fa6e72e1b3a681e284679db6c37661a96d0ddb7738c028201000000f0ca052a01000000034f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa");
+        assert_eq!(
+            spend().hash().to_string(),
+            "491289231af7db515b818348b06342cc65d49372fe159f025af5e5ba7905f2d8"
+        );
+    }
+
+    #[test]
+    fn merkle_root_vector() {
+        let root = MerkleRoot::calculate(&[coinbase(), spend()]);
+        assert_eq!(
+            root.0.to_string(),
+            "bb0b2c237ea4674d70fb174c657c9066f051c40ccd14f3b403cdc5a638b582a6"
+        );
+    }
+}
@@ -1,6 +1,7 @@
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, Deserialize, Serialize)]
 pub enum BtcError {
     #[error("Invalid transaction")]
     InvalidTransaction,
@@ -8,6 +9,8 @@ pub enum BtcError {
     InvalidBlock,
     #[error("Invalid block header")]
     InvalidBlockHeader,
+    #[error("Block hash does not meet the target")]
+    InvalidProofOfWork,
     #[error("Invalid transaction input")]
     InvalidTransactionInput,
     #[error("Invalid transaction output")]
@@ -22,6 +25,51 @@ pub enum BtcError {
     InvalidPublicKey,
     #[error("Invalid private key")]
     InvalidPrivateKey,
+    #[error("Transaction spends an unknown or already spent output")]
+    MissingInput,
+    #[error("Fee rate below the minimum relay fee rate")]
+    FeeTooLow,
+    #[error("Transaction already in mempool")]
+    AlreadyInMempool,
+    #[error("Replacement does not pay more fees than the transactions it replaces")]
+    ReplacementFeeTooLow,
+    #[error("Replacement does not pay a higher fee rate than the transactions it replaces")]
+    ReplacementFeeRateTooLow,
+    #[error("Replacement would evict too many transactions")]
+    TooManyReplacements,
+    #[error("Replacement spends an output of a transaction it replaces")]
+    ReplacementSpendsConflict,
+    #[error("Transaction has too many unconfirmed ancestors")]
+    TooManyAncestors,
+    #[error("Transaction would give an unconfirmed ancestor too many descendants")]
+    TooManyDescendants,
+    #[error("Mempool full")]
+    MempoolFull,
 }
 
 pub type Result<T> = std::result::Result<T, BtcError>;
+
+/// Errors sending or receiving a `Message`
+#[derive(Error, Debug)]
+pub enum NetworkError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Frame of {0} bytes is larger than allowed")]
+    FrameTooLarge(usize),
+    #[error("Connection closed in the middle of a frame")]
+    TruncatedFrame,
+    #[error("Timed out")]
+    Timeout,
+    #[error("Unexpected message")]
+    UnexpectedMessage,
+    #[error("Peer is on another network (magic {0:#x})")]
+    WrongNetwork(u32),
+    #[error("Peer speaks unsupported protocol version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Connected to ourselves")]
+    SelfConnection,
+    #[error("Failed to encode message: {0}")]
+    Encode(String),
+    #[error("Failed to decode message: {0}")]
+    Decode(String),
+}
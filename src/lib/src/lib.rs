@@ -1,10 +1,20 @@
-use serde::{Deserialize, Serialize};
-use uint::construct_uint;
+mod uint {
+    // the expansion of `construct_uint!` trips newer lints
+    #![allow(
+        deprecated,
+        semicolon_in_expressions_from_non_local_macros,
+        clippy::assign_op_pattern,
+        clippy::manual_div_ceil
+    )]
+    use serde::{Deserialize, Serialize};
+    use uint::construct_uint;
 
-construct_uint! {
-    #[derive(Serialize, Deserialize)]
-    pub struct U256(4);
+    construct_uint! {
+        #[derive(Serialize, Deserialize)]
+        pub struct U256(4);
+    }
 }
+pub use crate::uint::U256;
 
 // initial reward in bitcoin - multiply by 10^8 to get satoshis
 pub const INITIAL_REWARD: u64 = 50;
@@ -23,11 +33,27 @@ pub const MIN_TARGET: U256 = U256([
 pub const DIFFICULTY_UPDATE_INTERVAL: u64 = 50;
 // maximum mempool transaction age in seconds
 pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
+// maximum encoded size of all mempool transactions in bytes
+pub const MAX_MEMPOOL_SIZE: usize = 1_000_000;
+// minimum fee in satoshis per byte for a transaction to enter the mempool
+pub const MIN_RELAY_FEE_RATE: u64 = 1;
+// maximum amount of mempool transactions a replacement may evict
+pub const MAX_REPLACEMENTS: usize = 100;
+// maximum amount of unconfirmed transactions a mempool transaction
+// may depend on, itself included
+pub const MAX_MEMPOOL_ANCESTORS: usize = 25;
+// maximum amount of unconfirmed transactions that may depend on a
+// mempool transaction, itself included
+pub const MAX_MEMPOOL_DESCENDANTS: usize = 25;
 // maximum amount of transactions allowed in a block
 pub const BLOCK_TRANSACTION_CAP: usize = 20;
+// maximum amount of blocks kept while waiting for their parent
+pub const MAX_ORPHAN_BLOCKS: usize = 100;
 
 pub mod crypto;
+pub mod encoding;
 pub mod error;
+pub mod mining;
 pub mod network;
 pub mod sha256;
 pub mod types;
@@ -0,0 +1,237 @@
+use crate::types::{BlockHeader, HeaderHasher};
+use chrono::Utc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// nonces a worker tries between looking at the stop flags
+const WORKER_BATCH: u64 = 10_000;
+// how often the hashrate is reported while mining
+const HASHRATE_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Stops a running `Miner::mine` from another thread or task
+#[derive(Clone, Debug, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Outcome of a mining run
+#[derive(Clone, Debug)]
+pub struct Mined {
+    /// the header with a nonce matching its target,
+    /// None if mining was cancelled
+    pub header: Option<BlockHeader>,
+    /// nonces tried by all workers together
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl Mined {
+    /// Hashes per second
+    pub fn hashrate(&self) -> f64 {
+        hashrate(self.hashes, self.elapsed)
+    }
+}
+
+fn hashrate(hashes: u64, elapsed: Duration) -> f64 {
+    hashes as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+/// Mines a header on several threads, each searching its own
+/// part of the nonce space
+#[derive(Clone, Debug)]
+pub struct Miner {
+    threads: usize,
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+        }
+    }
+
+    /// One thread per available core
+    pub fn with_available_parallelism() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, |threads| threads.get()))
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Search for a nonce that makes `header` hash below its target.
+    /// Blocks until one is found or `cancel` is set, calling
+    /// `on_hashrate` with the hashes per second every few seconds.
+    /// Once the whole nonce space is searched the timestamp moves on
+    /// and the search starts over
+    pub fn mine<F: FnMut(f64)>(
+        &self,
+        mut header: BlockHeader,
+        cancel: &Cancel,
+        mut on_hashrate: F,
+    ) -> Mined {
+        let start = Instant::now();
+        let hashes = AtomicU64::new(0);
+        let mut last_report = (start, 0);
+        loop {
+            let nonce = self.search(&header, cancel, &hashes, |hashes| {
+                let now = Instant::now();
+                if now - last_report.0 >= HASHRATE_REPORT_INTERVAL {
+                    on_hashrate(hashrate(hashes - last_report.1, now - last_report.0));
+                    last_report = (now, hashes);
+                }
+            });
+            if let Some(nonce) = nonce {
+                header.nonce = nonce;
+                break;
+            }
+            if cancel.is_cancelled() {
+                return Mined {
+                    header: None,
+                    hashes: hashes.into_inner(),
+                    elapsed: start.elapsed(),
+                };
+            }
+            header.timestamp = header
+                .timestamp
+                .saturating_add(1)
+                .max(Utc::now().timestamp() as u32);
+        }
+        Mined {
+            header: Some(header),
+            hashes: hashes.into_inner(),
+            elapsed: start.elapsed(),
+        }
+    }
+
+    /// Search the whole nonce space once, returns the first
+    /// matching nonce any worker finds
+    fn search<F: FnMut(u64)>(
+        &self,
+        header: &BlockHeader,
+        cancel: &Cancel,
+        hashes: &AtomicU64,
+        mut on_tick: F,
+    ) -> Option<u64> {
+        let stop = AtomicBool::new(false);
+        let span = u64::MAX / self.threads as u64;
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            for worker in 0..self.threads as u64 {
+                let first = worker * span;
+                let last = if worker == self.threads as u64 - 1 {
+                    u64::MAX
+                } else {
+                    first + span - 1
+                };
+                let sender = sender.clone();
+                let stop = &stop;
+                scope.spawn(move || {
+                    let found = Self::work(header, first, last, stop, cancel, hashes);
+                    // the receiver only goes away once a nonce was found
+                    let _ = sender.send(found);
+                });
+            }
+            drop(sender);
+
+            let mut running = self.threads;
+            while running > 0 {
+                match receiver.recv_timeout(Duration::from_millis(100)) {
+                    Ok(Some(nonce)) => {
+                        stop.store(true, Ordering::Relaxed);
+                        return Some(nonce);
+                    }
+                    Ok(None) => running -= 1,
+                    Err(mpsc::RecvTimeoutError::Timeout) => on_tick(hashes.load(Ordering::Relaxed)),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None
+        })
+    }
+
+    /// Try the nonces `first..=last` until one matches,
+    /// another worker succeeded or mining is cancelled
+    fn work(
+        header: &BlockHeader,
+        first: u64,
+        last: u64,
+        stop: &AtomicBool,
+        cancel: &Cancel,
+        hashes: &AtomicU64,
+    ) -> Option<u64> {
+        let mut hasher = HeaderHasher::new(header);
+        let mut nonce = first;
+        loop {
+            let batch_last = last.min(nonce.saturating_add(WORKER_BATCH - 1));
+            for candidate in nonce..=batch_last {
+                if hasher.matches_target(candidate) {
+                    hashes.fetch_add(candidate - nonce + 1, Ordering::Relaxed);
+                    return Some(candidate);
+                }
+            }
+            hashes.fetch_add(batch_last - nonce + 1, Ordering::Relaxed);
+            if batch_last == last || stop.load(Ordering::Relaxed) || cancel.is_cancelled() {
+                return None;
+            }
+            nonce = batch_last + 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sha256::Hash;
+    use crate::types::Transaction;
+    use crate::util::MerkleRoot;
+    use crate::U256;
+
+    fn header(target: U256) -> BlockHeader {
+        BlockHeader::new(
+            Utc::now(),
+            0,
+            Hash::zero(),
+            MerkleRoot::calculate(&[Transaction::coinbase(0, vec![])]),
+            target,
+        )
+    }
+
+    #[test]
+    fn finds_matching_nonce() {
+        // a nonce matches once in about 256 tries
+        let target = U256::MAX >> 8;
+        let mined = Miner::new(4).mine(header(target), &Cancel::new(), |_| {});
+        let header = mined.header.unwrap();
+        assert!(header.hash().matches_target(target));
+        assert!(mined.hashes > 0);
+    }
+
+    #[test]
+    fn stops_when_cancelled() {
+        let cancel = Cancel::new();
+        let canceller = cancel.clone();
+        let handle = thread::spawn(move || {
+            // nothing is below a zero target
+            Miner::new(2).mine(header(U256::zero()), &canceller, |_| {})
+        });
+        thread::sleep(Duration::from_millis(50));
+        cancel.cancel();
+        let mined = handle.join().unwrap();
+        assert!(mined.header.is_none());
+    }
+}
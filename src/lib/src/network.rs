@@ -1,20 +1,135 @@
+use bytes::{Buf, BufMut, BytesMut};
 use serde::{Deserialize, Serialize};
-use std::io::{Error as IoError, Read, Write};
+use std::io::{ErrorKind as IoErrorKind, Read, Write};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+use tokio_util::codec::{Decoder, Encoder};
 
 use crate::{
     crypto::PublicKey,
-    types::{Block, Transaction, TransactionOutput},
+    error::{BtcError, NetworkError},
+    sha256::Hash,
+    types::{Block, BlockHeader, OutPoint, Transaction, TransactionOutput},
 };
 
+/// Largest message accepted, in bytes without the length prefix
+pub const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+/// How long the rest of a message may take once its first byte arrived
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long sending a message may take
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Bytes of the big endian length every message is prefixed with
+const LENGTH_PREFIX: usize = 8;
+/// Identifies the network, peers with another magic are on another chain
+pub const NETWORK_MAGIC: u32 = 0xb7c1_1b01;
+/// Version of the protocol this library speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version we can talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// How long the version handshake may take
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most headers sent for one GetHeaders
+pub const MAX_HEADERS: usize = 2000;
+/// Most blocks asked for with one GetBlocks
+pub const MAX_BLOCKS: usize = 16;
+
+/// What each side of a connection announces before anything else
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Version {
+    pub magic: u32,
+    pub protocol_version: u32,
+    /// height of the sender's active chain
+    pub best_height: u64,
+    /// name and version of the sender's software
+    pub user_agent: String,
+    /// random per node, seeing our own nonce means
+    /// we connected to ourselves
+    pub nonce: u64,
+    /// the sender is a node and wants new transactions
+    /// and blocks announced to it
+    pub relay: bool,
+}
+
+impl Version {
+    /// Our version with a random nonce
+    pub fn new(best_height: u64, user_agent: String, relay: bool) -> Self {
+        Self {
+            magic: NETWORK_MAGIC,
+            protocol_version: PROTOCOL_VERSION,
+            best_height,
+            user_agent,
+            nonce: rand::random(),
+            relay,
+        }
+    }
+
+    /// The protocol version a connection to the sender of this
+    /// version speaks, the newest both sides know
+    pub fn negotiated(&self) -> u32 {
+        self.protocol_version.min(PROTOCOL_VERSION)
+    }
+
+    /// Exchange `Version` and `Verack` with the other side of a fresh
+    /// connection, before any other message. Fails if the peer is on
+    /// another network, speaks a protocol version we do not support
+    /// or is ourselves. Returns the peer's version
+    pub async fn handshake(
+        self,
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    ) -> Result<Version, NetworkError> {
+        timeout(HANDSHAKE_TIMEOUT, async {
+            let nonce = self.nonce;
+            Message::Version(self).send_async(stream).await?;
+            let peer = match Message::receive_async(stream).await? {
+                Message::Version(peer) => peer,
+                _ => return Err(NetworkError::UnexpectedMessage),
+            };
+            if peer.magic != NETWORK_MAGIC {
+                return Err(NetworkError::WrongNetwork(peer.magic));
+            }
+            if peer.protocol_version < MIN_PROTOCOL_VERSION {
+                return Err(NetworkError::UnsupportedVersion(peer.protocol_version));
+            }
+            if peer.nonce == nonce {
+                return Err(NetworkError::SelfConnection);
+            }
+            Message::Verack.send_async(stream).await?;
+            match Message::receive_async(stream).await? {
+                Message::Verack => Ok(peer),
+                _ => Err(NetworkError::UnexpectedMessage),
+            }
+        })
+        .await
+        .map_err(|_| NetworkError::Timeout)?
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Message {
+    /// First message on every connection, in both directions
+    Version(Version),
+    /// Accepts the other side's Version, the connection is ready
+    Verack,
+    /// Check that the other side is still there, it answers
+    /// with a Pong carrying the same nonce
+    Ping(u64),
+    /// The answer to Ping
+    Pong(u64),
     /// Fetch all UTXOs belonging to a publickey
     FetchUTXOs(PublicKey),
-    /// UTXOs belonging to a publickey, bool determines if marked
-    UTXOs(Vec<(TransactionOutput, bool)>),
+    /// UTXOs belonging to a publickey with their outpoints,
+    /// the first bool is set if a mempool transaction already
+    /// spends it, the second if it is on the chain rather than
+    /// created by a mempool transaction
+    UTXOs(Vec<(OutPoint, TransactionOutput, bool, bool)>),
     /// Send a transaction to the network
     SubmitTransaction(Transaction),
+    /// The answer to SubmitTransaction when the node took it
+    TransactionAccepted,
+    /// The answer to SubmitTransaction when the node refused it,
+    /// with the reason
+    TransactionRejected(BtcError),
     /// Broadcast a new transaction to otner nodes
     NewTransaction(Transaction),
     /// Task node to prepare the optimal block template
@@ -33,68 +148,381 @@ pub enum Message {
     DiscoverNodes,
     /// This is the response to DiscoverNodes
     NodeList(Vec<String>),
-    /// Ask a node whats the highest block it knows about
-    /// in comparison to the local blockchain
-    AskDifference(u32),
-    /// This is the response to AskDifference
-    Difference(i32),
     /// Ask a node to send a block with the specified height
     FetchBlock(usize),
     /// Broadcast a new block to other nodes
     NewBlock(Block),
+    /// Ask a node for up to this many headers of its active chain,
+    /// following the first block of the locator it has on it
+    GetHeaders(Vec<Hash>, u32),
+    /// This is the response to GetHeaders
+    Headers(Vec<BlockHeader>),
+    /// Ask a node for blocks by hash, up to `MAX_BLOCKS`
+    GetBlocks(Vec<Hash>),
+    /// This is the response to GetBlocks, the blocks the node
+    /// has in the order they were asked for
+    Blocks(Vec<Block>),
+    /// Ask a node for the addresses it banned, only
+    /// answered on connections from its own machine
+    ListBans,
+    /// Lift all bans, answered like ListBans
+    ClearBans,
+    /// The response to ListBans, and to ClearBans with the bans
+    /// lifted: addresses and when their ban ends, seconds since
+    /// the unix epoch
+    Bans(Vec<(String, i64)>),
 }
 
 // network.rs
 // We are going to use length-prefixed encoding for message
 // And we are going to use ciborium (CBOR) for serialization
 impl Message {
-    pub fn encode(&self) -> Result<Vec<u8>, ciborium::ser::Error<IoError>> {
+    pub fn encode(&self) -> Result<Vec<u8>, NetworkError> {
         let mut bytes: Vec<u8> = Vec::new();
-        ciborium::into_writer(self, &mut bytes)?;
+        ciborium::into_writer(self, &mut bytes).map_err(|e| NetworkError::Encode(e.to_string()))?;
+        if bytes.len() > MAX_FRAME_SIZE {
+            return Err(NetworkError::FrameTooLarge(bytes.len()));
+        }
         Ok(bytes)
     }
 
-    pub fn decode(data: &[u8]) -> Result<Self, ciborium::de::Error<IoError>> {
-        ciborium::from_reader(data)
+    pub fn decode(data: &[u8]) -> Result<Self, NetworkError> {
+        ciborium::from_reader(data).map_err(|e| NetworkError::Decode(e.to_string()))
+    }
+
+    /// The length a frame's prefix announces, checked against `max`
+    fn frame_len(prefix: [u8; LENGTH_PREFIX], max: usize) -> Result<usize, NetworkError> {
+        let len = u64::from_be_bytes(prefix);
+        if len > max as u64 {
+            return Err(NetworkError::FrameTooLarge(len as usize));
+        }
+        Ok(len as usize)
     }
 
-    pub fn send(&self, stream: &mut impl Write) -> Result<(), ciborium::ser::Error<IoError>> {
+    /// Blocking send, timeouts are up to the caller's stream
+    pub fn send(&self, stream: &mut impl Write) -> Result<(), NetworkError> {
         let bytes = self.encode()?;
-        let len = bytes.len();
+        let len = bytes.len() as u64;
         stream.write_all(&len.to_be_bytes())?;
         stream.write_all(&bytes)?;
         Ok(())
     }
 
+    /// Send a message, failing after `WRITE_TIMEOUT`
     pub async fn send_async(
         &self,
         stream: &mut (impl AsyncWrite + Unpin),
-    ) -> Result<(), ciborium::ser::Error<IoError>> {
+    ) -> Result<(), NetworkError> {
         let bytes = self.encode()?;
-        let len = bytes.len();
-        stream.write_all(&len.to_be_bytes()).await?;
-        stream.write_all(&bytes).await?;
+        let len = bytes.len() as u64;
+        timeout(WRITE_TIMEOUT, async {
+            stream.write_all(&len.to_be_bytes()).await?;
+            stream.write_all(&bytes).await
+        })
+        .await
+        .map_err(|_| NetworkError::Timeout)??;
 
         Ok(())
     }
-    pub fn receive(stream: &mut impl Read) -> Result<Self, ciborium::de::Error<IoError>> {
-        let mut len_bytes = [0u8; 8];
+
+    /// Blocking receive, timeouts are up to the caller's stream
+    pub fn receive(stream: &mut impl Read) -> Result<Self, NetworkError> {
+        let mut len_bytes = [0u8; LENGTH_PREFIX];
         stream.read_exact(&mut len_bytes)?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
+        let len = Self::frame_len(len_bytes, MAX_FRAME_SIZE)?;
         let mut buf = vec![0u8; len];
-        stream.read_exact(&mut buf)?;
+        stream.read_exact(&mut buf).map_err(truncated)?;
         Self::decode(&buf)
     }
 
+    /// Wait for the next message. Waiting for it to start is not
+    /// limited, once it has started it has to arrive within
+    /// `READ_TIMEOUT`
     pub async fn receive_async(
         stream: &mut (impl AsyncRead + Unpin),
-    ) -> Result<Self, ciborium::de::Error<IoError>> {
-        let mut len_bytes = [0u8; 8];
-        stream.read_exact(&mut len_bytes).await?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
-        let mut buf = vec![0u8; len];
-        stream.read_exact(&mut buf).await?;
+    ) -> Result<Self, NetworkError> {
+        let mut len_bytes = [0u8; LENGTH_PREFIX];
+        stream.read_exact(&mut len_bytes[..1]).await?;
+        let buf = timeout(READ_TIMEOUT, async {
+            stream
+                .read_exact(&mut len_bytes[1..])
+                .await
+                .map_err(truncated)?;
+            let len = Self::frame_len(len_bytes, MAX_FRAME_SIZE)?;
+            let mut buf = vec![0u8; len];
+            stream.read_exact(&mut buf).await.map_err(truncated)?;
+            Ok::<_, NetworkError>(buf)
+        })
+        .await
+        .map_err(|_| NetworkError::Timeout)??;
 
         Self::decode(&buf)
     }
 }
+
+/// Bytes a Blocks message of `count` blocks takes besides the
+/// blocks: the variant name and the header of the list, which
+/// grows with the number of entries as CBOR encodes it
+fn blocks_overhead(count: usize) -> usize {
+    let empty = Message::Blocks(vec![])
+        .encode()
+        .expect("BUG: impossible")
+        .len();
+    let list_header = match count {
+        0..=23 => 1,
+        24..=0xff => 2,
+        0x100..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => 9,
+    };
+    // the empty list's header is a single byte
+    empty - 1 + list_header
+}
+
+/// The leading `blocks` that fit in one Blocks message of at
+/// most `max_frame_size` bytes
+pub fn blocks_within(blocks: impl IntoIterator<Item = Block>, max_frame_size: usize) -> Vec<Block> {
+    let mut size = 0;
+    let mut within = vec![];
+    for block in blocks {
+        let mut bytes = vec![];
+        if ciborium::into_writer(&block, &mut bytes).is_err() {
+            break;
+        }
+        size += bytes.len();
+        if size + blocks_overhead(within.len() + 1) > max_frame_size {
+            break;
+        }
+        within.push(block);
+    }
+    within
+}
+
+/// The connection closing in the middle of a frame is a truncated frame
+fn truncated(error: std::io::Error) -> NetworkError {
+    if error.kind() == IoErrorKind::UnexpectedEof {
+        NetworkError::TruncatedFrame
+    } else {
+        NetworkError::Io(error)
+    }
+}
+
+/// Length-prefixed CBOR frames for `tokio_util::codec::Framed`,
+/// the same format as `Message::send_async` and `receive_async`
+#[derive(Clone, Copy, Debug)]
+pub struct MessageCodec {
+    max_frame_size: usize,
+}
+
+impl MessageCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self { max_frame_size }
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new(MAX_FRAME_SIZE)
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = NetworkError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, NetworkError> {
+        if src.len() < LENGTH_PREFIX {
+            return Ok(None);
+        }
+        let mut prefix = [0u8; LENGTH_PREFIX];
+        prefix.copy_from_slice(&src[..LENGTH_PREFIX]);
+        let len = Message::frame_len(prefix, self.max_frame_size)?;
+        if src.len() < LENGTH_PREFIX + len {
+            src.reserve(LENGTH_PREFIX + len - src.len());
+            return Ok(None);
+        }
+        src.advance(LENGTH_PREFIX);
+        let frame = src.split_to(len);
+        Message::decode(&frame).map(Some)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Message>, NetworkError> {
+        match self.decode(src)? {
+            Some(message) => Ok(Some(message)),
+            None if src.is_empty() => Ok(None),
+            None => Err(NetworkError::TruncatedFrame),
+        }
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = NetworkError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), NetworkError> {
+        let bytes = message.encode()?;
+        if bytes.len() > self.max_frame_size {
+            return Err(NetworkError::FrameTooLarge(bytes.len()));
+        }
+        dst.reserve(LENGTH_PREFIX + bytes.len());
+        dst.put_u64(bytes.len() as u64);
+        dst.put_slice(&bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(message: &Message) -> Vec<u8> {
+        let mut bytes = vec![];
+        message.send(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn codec_reads_what_send_writes() {
+        let mut codec = MessageCodec::default();
+        let mut src = BytesMut::from(&frame(&Message::Ping(7))[..]);
+        src.extend_from_slice(&frame(&Message::DiscoverNodes));
+        // the second frame is incomplete
+        src.truncate(src.len() - 1);
+
+        assert!(matches!(codec.decode(&mut src), Ok(Some(Message::Ping(7)))));
+        assert!(matches!(codec.decode(&mut src), Ok(None)));
+        assert!(matches!(
+            codec.decode_eof(&mut src),
+            Err(NetworkError::TruncatedFrame)
+        ));
+
+        let mut dst = BytesMut::new();
+        codec.encode(Message::Pong(3), &mut dst).unwrap();
+        assert_eq!(dst.to_vec(), frame(&Message::Pong(3)));
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        // a length prefix alone must not make us allocate
+        let mut src = BytesMut::from(&u64::MAX.to_be_bytes()[..]);
+        assert!(matches!(
+            MessageCodec::default().decode(&mut src),
+            Err(NetworkError::FrameTooLarge(_))
+        ));
+        assert!(matches!(
+            Message::receive(&mut &u64::MAX.to_be_bytes()[..]),
+            Err(NetworkError::FrameTooLarge(_))
+        ));
+
+        let mut small = MessageCodec::new(4);
+        assert!(matches!(
+            small.encode(Message::NodeList(vec!["a:1".into()]), &mut BytesMut::new()),
+            Err(NetworkError::FrameTooLarge(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn receive_async_reports_truncated_frames() {
+        let mut bytes = frame(&Message::Ping(7));
+        bytes.pop();
+        assert!(matches!(
+            Message::receive_async(&mut bytes.as_slice()).await,
+            Err(NetworkError::TruncatedFrame)
+        ));
+    }
+
+    #[tokio::test]
+    async fn handshake_exchanges_versions() {
+        let (mut left, mut right) = tokio::io::duplex(1024);
+        let ours = Version::new(3, "left".into(), true);
+        let theirs = Version::new(5, "right".into(), false);
+        let (left, right) = tokio::join!(ours.handshake(&mut left), theirs.handshake(&mut right));
+        let peer = left.unwrap();
+        assert_eq!(peer.best_height, 5);
+        assert!(!peer.relay);
+        assert_eq!(peer.negotiated(), PROTOCOL_VERSION);
+        assert_eq!(right.unwrap().user_agent, "left");
+    }
+
+    #[tokio::test]
+    async fn handshake_rejects_other_networks_and_ourselves() {
+        let (mut left, mut right) = tokio::io::duplex(1024);
+        let other_network = Version {
+            magic: NETWORK_MAGIC + 1,
+            ..Version::new(0, "right".into(), true)
+        };
+        tokio::spawn(async move { other_network.handshake(&mut right).await });
+        let ours = Version::new(0, "left".into(), true);
+        assert!(matches!(
+            ours.handshake(&mut left).await,
+            Err(NetworkError::WrongNetwork(_))
+        ));
+
+        let (mut left, mut right) = tokio::io::duplex(1024);
+        let ours = Version::new(0, "node".into(), true);
+        let (left, _) = tokio::join!(
+            ours.clone().handshake(&mut left),
+            ours.handshake(&mut right)
+        );
+        assert!(matches!(left, Err(NetworkError::SelfConnection)));
+    }
+
+    /// A block with just a coinbase paying `key`
+    fn block(height: u64, key: &crate::crypto::PrivateKey) -> Block {
+        let outputs = vec![TransactionOutput {
+            value: 1,
+            pubkey: key.public_key(),
+        }];
+        let transactions = vec![Transaction::coinbase(height, outputs)];
+        let header = BlockHeader::new(
+            chrono::Utc::now(),
+            0,
+            Hash::zero(),
+            crate::util::MerkleRoot::calculate(&transactions),
+            crate::MIN_TARGET,
+        );
+        Block::new(header, transactions)
+    }
+
+    #[test]
+    fn blocks_stay_within_the_frame() {
+        let key = crate::crypto::PrivateKey::new_key();
+        let blocks: Vec<Block> = (0..4).map(|height| block(height, &key)).collect();
+        let one = Message::Blocks(blocks[..1].to_vec())
+            .encode()
+            .unwrap()
+            .len();
+        let max_frame_size = one * 5 / 2;
+
+        let within = blocks_within(blocks.clone(), max_frame_size);
+        assert_eq!(within.len(), 2);
+        assert_eq!(within[1].hash(), blocks[1].hash());
+        assert!(Message::Blocks(within).encode().unwrap().len() <= max_frame_size);
+        assert_eq!(blocks_within(blocks.clone(), MAX_FRAME_SIZE).len(), 4);
+
+        // frames the blocks fill to the byte, and one byte short
+        for count in 1..=blocks.len() {
+            let exact = Message::Blocks(blocks[..count].to_vec())
+                .encode()
+                .unwrap()
+                .len();
+            assert_eq!(blocks_within(blocks.clone(), exact).len(), count);
+            assert_eq!(blocks_within(blocks.clone(), exact - 1).len(), count - 1);
+        }
+    }
+
+    #[test]
+    fn counts_the_list_header_of_long_block_lists() {
+        let block = block(0, &crate::crypto::PrivateKey::new_key());
+        let mut bytes = vec![];
+        ciborium::into_writer(&block, &mut bytes).unwrap();
+        for count in [0, 23, 24, 255, 256] {
+            assert_eq!(
+                Message::Blocks(vec![block.clone(); count])
+                    .encode()
+                    .unwrap()
+                    .len(),
+                blocks_overhead(count) + count * bytes.len()
+            );
+        }
+    }
+}
@@ -1,21 +1,28 @@
 use crate::U256;
 use serde;
-use sha256::digest;
+use sha2::{Digest, Sha256};
 use std::fmt;
 
-#[derive(Clone, Copy, serde::Serialize, serde::Deserialize, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Hash(U256);
 
 impl Hash {
+    /// Hash the CBOR of any value. Consensus data is hashed
+    /// with `hash_bytes` over its `Encode` encoding instead
+    #[allow(clippy::self_named_constructors)]
     pub fn hash<T: serde::Serialize>(data: &T) -> Self {
         let mut serialized: Vec<u8> = vec![];
         if let Err(e) = ciborium::into_writer(data, &mut serialized) {
             panic!("Failed to serialize data: {:?}. This should not happen", e);
         }
-        let hash = digest(&serialized); // compute hash of the CBOR
-        let hash_bytes = hex::decode(hash).unwrap(); // decode to hex
-        let hash_array: [u8; 32] = hash_bytes.as_slice().try_into().unwrap(); // as array of bytes
-        Hash(U256::try_from(hash_array).unwrap()) // conver to U256
+        Self::hash_bytes(&serialized)
+    }
+    pub fn hash_bytes(data: &[u8]) -> Self {
+        Self::from_digest(&Sha256::digest(data).into())
+    }
+    /// The digest read as a big endian number
+    pub fn from_digest(digest: &[u8; 32]) -> Self {
+        Hash(U256::from_big_endian(digest))
     }
     pub fn as_bytes(&self) -> [u8; 32] {
         let mut bytes: Vec<u8> = vec![0; 32];
@@ -1,7 +1,9 @@
 mod block;
 mod blockchain;
+mod mempool;
 mod transaction;
 
-pub use block::{Block, BlockHeader};
-pub use blockchain::Blockchain;
-pub use transaction::{Transaction, TransactionInput, TransactionOutput};
+pub use block::{Block, BlockHeader, HeaderHasher};
+pub use blockchain::{AddedBlock, Blockchain, HeaderCheck, Reorg};
+pub use mempool::{Mempool, MempoolEntry, Package};
+pub use transaction::{OutPoint, SigHash, Transaction, TransactionInput, TransactionOutput};
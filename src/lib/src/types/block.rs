@@ -1,17 +1,19 @@
+use crate::encoding::Encode;
 use crate::error::{BtcError, Result};
 use crate::sha256::Hash;
-use crate::types::transaction::{Transaction, TransactionOutput};
+use crate::types::transaction::{OutPoint, Transaction, TransactionOutput};
 use crate::util::{MerkleRoot, Saveable};
 use crate::U256;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BlockHeader {
-    /// when the block was created
-    pub timestamp: DateTime<Utc>,
+    /// when the block was created, seconds since the unix epoch
+    pub timestamp: u32,
     /// Used to mine the block
     pub nonce: u64,
     pub prev_block_hash: Hash,
@@ -22,6 +24,10 @@ pub struct BlockHeader {
 }
 
 impl BlockHeader {
+    /// Length of the consensus encoding of a header
+    pub const SIZE: usize = 112;
+
+    /// The timestamp is kept with second precision
     pub fn new(
         timestamp: DateTime<Utc>,
         nonce: u64,
@@ -30,7 +36,7 @@ impl BlockHeader {
         target: U256,
     ) -> Self {
         Self {
-            timestamp,
+            timestamp: timestamp.timestamp() as u32,
             nonce,
             prev_block_hash,
             merkle_root,
@@ -39,7 +45,8 @@ impl BlockHeader {
     }
 
     pub fn mine(&mut self, steps: usize) -> bool {
-        if self.hash().matches_target(self.target) {
+        let mut hasher = HeaderHasher::new(self);
+        if hasher.matches_target(self.nonce) {
             return true;
         }
         for _ in 0..steps {
@@ -47,17 +54,71 @@ impl BlockHeader {
                 self.nonce = new_nonce;
             } else {
                 self.nonce = 0;
-                self.timestamp = Utc::now();
+                self.timestamp = Utc::now().timestamp() as u32;
+                hasher = HeaderHasher::new(self);
             }
-            if self.hash().matches_target(self.target) {
+            if hasher.matches_target(self.nonce) {
                 return true;
             }
         }
-        return false;
+        false
     }
 
+    /// Hash of the consensus encoding
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        Hash::hash_bytes(&self.encoded())
+    }
+
+    /// When the block was created
+    pub fn time(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp(self.timestamp.into(), 0).expect("BUG: u32 is always in range")
+    }
+
+    /// Expected number of hashes needed to mine a block at this
+    /// header's target, i.e. 2^256 / (target + 1)
+    pub fn work(&self) -> U256 {
+        (!self.target / (self.target + 1)) + 1
+    }
+}
+
+/// Hashes one header for many nonces. The header is encoded once,
+/// only the nonce at its end is rewritten, and the SHA-256 state
+/// after the first 64 bytes (which hold no nonce) is reused
+#[derive(Clone)]
+pub struct HeaderHasher {
+    midstate: Sha256,
+    tail: [u8; BlockHeader::SIZE - 64],
+    target: [u8; 32],
+}
+
+impl HeaderHasher {
+    pub fn new(header: &BlockHeader) -> Self {
+        let encoded = header.encoded();
+        let mut midstate = Sha256::new();
+        midstate.update(&encoded[..64]);
+        let mut target = [0u8; 32];
+        header.target.to_big_endian(&mut target);
+        Self {
+            midstate,
+            tail: encoded[64..]
+                .try_into()
+                .expect("BUG: header has a fixed size"),
+            target,
+        }
+    }
+
+    pub fn hash(&mut self, nonce: u64) -> [u8; 32] {
+        let nonce_start = self.tail.len() - 8;
+        self.tail[nonce_start..].copy_from_slice(&nonce.to_le_bytes());
+        let mut hasher = self.midstate.clone();
+        hasher.update(self.tail);
+        hasher.finalize().into()
+    }
+
+    /// Whether the header with `nonce` hashes below the target.
+    /// Both are big endian, so the bytes compare like the numbers
+    pub fn matches_target(&mut self, nonce: u64) -> bool {
+        self.hash(nonce) < self.target
     }
 }
 
@@ -85,118 +146,125 @@ impl Block {
             transactions,
         }
     }
+    /// A block is identified by its header's hash
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        self.header.hash()
     }
 
-    pub fn calculate_miner_fee(
-        &self,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
-    ) -> Result<u64> {
-        let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
-        let mut outputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+    /// The output `outpoint` refers to, either unspent on the chain or
+    /// created by a transaction of this block before `index`
+    fn prev_output<'a>(
+        &'a self,
+        utxos: &'a HashMap<OutPoint, TransactionOutput>,
+        index: usize,
+        outpoint: &OutPoint,
+    ) -> Option<&'a TransactionOutput> {
+        utxos.get(outpoint).or_else(|| {
+            // coinbase outputs can not be spent in their own block
+            self.transactions[1..index]
+                .iter()
+                .find(|transaction| transaction.hash() == outpoint.txid)
+                .and_then(|transaction| transaction.outputs.get(outpoint.index as usize))
+        })
+    }
+
+    pub fn calculate_miner_fee(&self, utxos: &HashMap<OutPoint, TransactionOutput>) -> Result<u64> {
+        let mut inputs: HashMap<OutPoint, TransactionOutput> = HashMap::new();
+        let mut output_value: u64 = 0;
         // Check every transaction after coinbase
-        for transaction in self.transactions.iter().skip(1) {
+        for (index, transaction) in self.transactions.iter().enumerate().skip(1) {
             for input in &transaction.inputs {
-                let prev_output = utxos
-                    .get(&input.pre_transaction_output_hash)
-                    .map(|(_, output)| output);
-
-                if prev_output.is_none() {
-                    return Err(BtcError::InvalidTransaction);
-                }
-                let prev_output = prev_output.unwrap();
-                if inputs.contains_key(&input.pre_transaction_output_hash) {
-                    return Err(BtcError::InvalidTransaction);
-                }
-                inputs.insert(input.pre_transaction_output_hash, prev_output.clone());
-            }
-            for output in &transaction.outputs {
-                if outputs.contains_key(&output.hash()) {
+                let prev_output = self
+                    .prev_output(utxos, index, &input.prev_output)
+                    .ok_or(BtcError::InvalidTransaction)?;
+                if inputs.contains_key(&input.prev_output) {
                     return Err(BtcError::InvalidTransaction);
                 }
-                outputs.insert(output.hash(), output.clone());
+                inputs.insert(input.prev_output, prev_output.clone());
             }
+            let value = total(transaction.outputs.iter())?;
+            output_value = output_value
+                .checked_add(value)
+                .ok_or(BtcError::InvalidTransaction)?;
         }
 
-        //
-        let input_value: u64 = inputs.values().map(|output| output.value).sum();
-        let output_value: u64 = outputs.values().map(|output| output.value).sum();
-        Ok(input_value - output_value)
+        let input_value = total(inputs.values())?;
+        input_value
+            .checked_sub(output_value)
+            .ok_or(BtcError::InvalidTransaction)
     }
 
     pub fn verify_coinbase_transaction(
         &self,
         predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        utxos: &HashMap<OutPoint, TransactionOutput>,
     ) -> Result<()> {
         let coinbase_transaction = &self.transactions[0];
-        if coinbase_transaction.inputs.len() != 0 {
+        if !coinbase_transaction.inputs.is_empty() {
             return Err(BtcError::InvalidTransaction);
         }
-        if coinbase_transaction.outputs.len() == 0 {
+        if coinbase_transaction.outputs.is_empty() {
+            return Err(BtcError::InvalidTransaction);
+        }
+        // the height keeps this coinbase's txid apart from all others
+        if coinbase_transaction.coinbase_height != Some(predicted_block_height) {
             return Err(BtcError::InvalidTransaction);
         }
         let miner_fee = self.calculate_miner_fee(utxos)?;
         let block_reward = crate::INITIAL_REWARD * 10u64.pow(8)
             / 2u64.pow((predicted_block_height / crate::HALVING_INTERVAL) as u32);
-        let total_coinbase_outputs: u64 = coinbase_transaction
-            .outputs
-            .iter()
-            .map(|output| output.value)
-            .sum();
-        if total_coinbase_outputs != block_reward + miner_fee {
+        let total_coinbase_outputs = total(coinbase_transaction.outputs.iter())?;
+        if Some(total_coinbase_outputs) != block_reward.checked_add(miner_fee) {
             return Err(BtcError::InvalidTransaction);
         }
         Ok(())
     }
 
     /// verify transaction inputs in the tx are:
-    /// 1) on the block
+    /// 1) on the chain or created earlier in the block
     /// 2) have not been spent
     pub fn verify_transactions(
         &self,
         block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        utxos: &HashMap<OutPoint, TransactionOutput>,
     ) -> Result<()> {
-        if (&self.transactions).is_empty() {
+        if self.transactions.is_empty() {
             return Err(BtcError::InvalidTransaction);
         }
         self.verify_coinbase_transaction(block_height, utxos)?;
 
-        let mut input_hashes: HashSet<Hash> = HashSet::new();
+        let mut spent: HashSet<OutPoint> = HashSet::new();
 
-        for transaction in self.transactions.iter().skip(1) {
+        for (position, transaction) in self.transactions.iter().enumerate().skip(1) {
             // skip the coinbase tx
-            let mut input_value = 0;
-            let mut output_value = 0;
+            // only the first transaction may be a coinbase
+            if transaction.coinbase_height.is_some() {
+                return Err(BtcError::InvalidTransaction);
+            }
+            let mut input_value: u64 = 0;
 
-            for input in &transaction.inputs {
-                let prev_output: Option<&TransactionOutput> = utxos
-                    .get(&input.pre_transaction_output_hash)
-                    .map(|(_, output)| output);
+            for (index, input) in transaction.inputs.iter().enumerate() {
+                let prev_output: Option<&TransactionOutput> =
+                    self.prev_output(utxos, position, &input.prev_output);
                 if prev_output.is_none() {
                     return Err(BtcError::InvalidTransaction);
                 }
                 let prev_output: &TransactionOutput = prev_output.unwrap();
                 // no double spending
-                if input_hashes.contains(&input.pre_transaction_output_hash) {
+                if spent.contains(&input.prev_output) {
                     return Err(BtcError::InvalidTransactionInput);
                 }
                 // check if signature is valid
-                if !input
-                    .signature
-                    .verify(&input.pre_transaction_output_hash, &prev_output.pubkey)
-                {
+                if !transaction.verify_input(index, prev_output) {
                     return Err(BtcError::InvalidTransactionInput);
                 }
-                input_value += prev_output.value;
+                input_value = input_value
+                    .checked_add(prev_output.value)
+                    .ok_or(BtcError::InvalidTransactionInput)?;
                 //keep track of inputs we've seen
-                input_hashes.insert(input.pre_transaction_output_hash.clone());
-            }
-            for output in &transaction.outputs {
-                output_value += output.value
+                spent.insert(input.prev_output);
             }
+            let output_value = total(transaction.outputs.iter())?;
             // delta is the transaction fee
             if input_value < output_value {
                 return Err(BtcError::InvalidTransactionInput);
@@ -205,3 +273,71 @@ impl Block {
         Ok(())
     }
 }
+
+/// Value of `outputs` together, an invalid
+/// transaction if it does not fit in a u64
+fn total<'a>(mut outputs: impl Iterator<Item = &'a TransactionOutput>) -> Result<u64> {
+    outputs
+        .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+        .ok_or(BtcError::InvalidTransaction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::types::{SigHash, TransactionInput};
+
+    #[test]
+    fn header_hasher_matches_hash() {
+        let mut header = BlockHeader::new(
+            Utc::now(),
+            0,
+            Hash::hash_bytes(b"parent"),
+            MerkleRoot(Hash::hash_bytes(b"transactions")),
+            crate::MIN_TARGET,
+        );
+        let mut hasher = HeaderHasher::new(&header);
+        for nonce in [0, 1, 0xff, u64::MAX] {
+            header.nonce = nonce;
+            let hash = header.hash();
+            assert_eq!(Hash::from_digest(&hasher.hash(nonce)), hash);
+            assert_eq!(
+                hasher.matches_target(nonce),
+                hash.matches_target(header.target)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_output_values() {
+        let key = PrivateKey::new_key();
+        let output = |value| TransactionOutput {
+            value,
+            pubkey: key.public_key(),
+        };
+        let outpoint = OutPoint::new(Hash::hash_bytes(b"funding"), 0);
+        let utxos = HashMap::from([(outpoint, output(10))]);
+        let input = TransactionInput {
+            prev_output: outpoint,
+            signature: Signature::sign(&Hash::hash_bytes(b"message"), &key),
+            sighash: SigHash::ALL,
+        };
+        let transactions = vec![
+            Transaction::coinbase(0, vec![output(1)]),
+            Transaction::new(vec![input], vec![output(u64::MAX), output(11)]),
+        ];
+        let block = Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(&transactions),
+                crate::MIN_TARGET,
+            ),
+            transactions,
+        );
+        assert!(block.calculate_miner_fee(&utxos).is_err());
+        assert!(block.verify_transactions(0, &utxos).is_err());
+    }
+}
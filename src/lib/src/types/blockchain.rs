@@ -1,31 +1,158 @@
+use crate::crypto::PublicKey;
 use crate::error::{BtcError, Result};
 use crate::sha256::Hash;
-use crate::types::block::Block;
-use crate::types::transaction::{Transaction, TransactionOutput};
+use crate::types::block::{Block, BlockHeader};
+use crate::types::mempool::{Mempool, MempoolEntry};
+use crate::types::transaction::{OutPoint, Transaction, TransactionOutput};
 use crate::util::{MerkleRoot, Saveable};
 use crate::U256;
 use bigdecimal::BigDecimal;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 
+/// Outputs spent by a block, enough to take the block
+/// back out of the utxo set
+#[derive(Clone, Debug, Default)]
+struct BlockUndo {
+    spent: Vec<(OutPoint, TransactionOutput)>,
+}
+
+/// What `Blockchain::add_block` did with a block
+#[derive(Debug, Default)]
+pub struct AddedBlock {
+    /// the block is new and went on the active chain or a side branch
+    pub accepted: bool,
+    /// the block's parent is unknown, it waits as an orphan
+    pub orphan: bool,
+    /// orphans built on the block that turned out invalid
+    pub rejected_orphans: Vec<(Hash, BtcError)>,
+    /// switches of the active chain to another branch, in order
+    pub reorgs: Vec<Reorg>,
+}
+
+/// A switch of the active chain to a branch with more work
+#[derive(Clone, Copy, Debug)]
+pub struct Reorg {
+    /// height of the first block that changed
+    pub height: usize,
+    pub disconnected: usize,
+    pub connected: usize,
+    /// transactions of the disconnected blocks that conflict with
+    /// the new chain or found no room in the mempool
+    pub dropped: usize,
+}
+
+/// A branch of headers checked a batch at a time, keeping only
+/// what the next header is checked against
+#[derive(Clone, Debug)]
+pub struct HeaderCheck {
+    // headers on the branch, the height of the next one
+    height: u64,
+    // the last header, none before a genesis block
+    last: Option<BlockHeader>,
+    last_hash: Hash,
+    // timestamp of the first block of the last header's
+    // difficulty period
+    period_start: u32,
+    // cumulative work of the branch
+    work: U256,
+}
+
+impl HeaderCheck {
+    /// Height the next header gets
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Cumulative work of the branch up to the last header
+    pub fn work(&self) -> U256 {
+        self.work
+    }
+
+    /// The target the next header has to have: the last header's,
+    /// adjusted every `DIFFICULTY_UPDATE_INTERVAL` blocks like
+    /// `Blockchain::try_adjust_target` does
+    fn next_target(&self) -> U256 {
+        let Some(last) = &self.last else {
+            return crate::MIN_TARGET;
+        };
+        if !self
+            .height
+            .is_multiple_of(crate::DIFFICULTY_UPDATE_INTERVAL)
+        {
+            return last.target;
+        }
+        Blockchain::adjusted_target(last.target, self.period_start, last.timestamp)
+    }
+
+    /// Add `headers` to the branch: each follows the header before
+    /// it and has the target the rules give it and valid proof of
+    /// work for it
+    pub fn extend(&mut self, headers: &[BlockHeader]) -> Result<()> {
+        for header in headers {
+            if header.prev_block_hash != self.last_hash {
+                return Err(BtcError::InvalidBlockHeader);
+            }
+            Blockchain::check_header(header, self.last.as_ref(), self.next_target())?;
+            if self
+                .height
+                .is_multiple_of(crate::DIFFICULTY_UPDATE_INTERVAL)
+            {
+                self.period_start = header.timestamp;
+            }
+            self.height += 1;
+            self.work += header.work();
+            self.last_hash = header.hash();
+            self.last = Some(header.clone());
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Blockchain {
-    // keep the utxos on the chain
-    // the bool marks the utxo as reserved by a transaction in mempool
-    // init to false,
-    utxos: HashMap<Hash, (bool, TransactionOutput)>,
+    // the outputs of the active chain not spent by it, rebuilt on load
+    #[serde(skip)]
+    utxos: HashMap<OutPoint, TransactionOutput>,
+    // the active chain, the branch with the most work
     blocks: Vec<Block>,
+    // undo data for each block of the active chain, rebuilt on load
+    #[serde(skip)]
+    undo: Vec<BlockUndo>,
+    // height of each block of the active chain, rebuilt on load
+    #[serde(skip)]
+    heights: HashMap<Hash, usize>,
     target: U256,
+    // blocks on branches other than the active chain
+    #[serde(default)]
+    side_blocks: HashMap<Hash, Block>,
+    // blocks whose parent we have not seen yet
+    #[serde(default, skip_serializing)]
+    orphans: HashMap<Hash, Block>,
+    // the orphans, oldest first
+    #[serde(skip)]
+    orphan_order: VecDeque<Hash>,
+    // cumulative work of the active chain and the side branches
+    // up to and including each block, rebuilt on load
     #[serde(default, skip_serializing)]
-    pub mempool: Vec<(DateTime<Utc>, Transaction)>,
+    chain_work: HashMap<Hash, U256>,
+    // blocks found invalid and their descendants, rejected right away
+    #[serde(skip)]
+    invalid: HashSet<Hash>,
+    #[serde(skip)]
+    mempool: Mempool,
 }
 
 impl Saveable for Blockchain {
     fn load<I: std::io::Read>(reader: I) -> std::io::Result<Self> {
-        ciborium::de::from_reader(reader)
-            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialias blockchain"))
+        let mut blockchain: Blockchain = ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to deserialias blockchain")
+        })?;
+        blockchain.rebuild_chain_work();
+        blockchain.rebuild_utoxs();
+        Ok(blockchain)
     }
 
     fn save<O: std::io::Write>(&self, writer: O) -> std::io::Result<()> {
@@ -33,39 +160,31 @@ impl Saveable for Blockchain {
             .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialias blockchain"))
     }
 }
-/*
-// fn mempool_sort_key2(&mut self) -> (Transaction -> u64) { todo!( ) }
-fn mempool_key(
-    utxos: &HashMap<Hash, (bool, TransactionOutput)>,
-) -> impl FnMut(&(DateTime<Utc>, Transaction)) -> u64 + use<'_> {
-    |(_, tx): &(DateTime<Utc>, Transaction)| {
-        let all_inputs: u64 = tx
-            .inputs
-            .iter()
-            .map(|input| {
-                utxos
-                    .get(&input.pre_transaction_output_hash)
-                    .expect("BUG: impossible")
-                    .value
-            })
-            .sum::<u64>();
-        let all_outputs: u64 = tx.outputs.iter().map(|output| output.value).sum();
-        let miner_fee: u64 = all_inputs - all_outputs;
-        miner_fee
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new()
     }
 }
-*/
+
 impl Blockchain {
     pub fn new() -> Self {
         Self {
             utxos: HashMap::new(),
             blocks: vec![],
+            undo: vec![],
+            heights: HashMap::new(),
             target: crate::MIN_TARGET,
-            mempool: vec![],
+            side_blocks: HashMap::new(),
+            orphans: HashMap::new(),
+            orphan_order: VecDeque::new(),
+            chain_work: HashMap::new(),
+            invalid: HashSet::new(),
+            mempool: Mempool::default(),
         }
     }
 
-    pub fn utxos(&self) -> &HashMap<Hash, (bool, TransactionOutput)> {
+    pub fn utxos(&self) -> &HashMap<OutPoint, TransactionOutput> {
         &self.utxos
     }
     pub fn blocks(&self) -> impl Iterator<Item = &Block> {
@@ -74,176 +193,749 @@ impl Blockchain {
     pub fn target(&self) -> U256 {
         self.target
     }
-    pub fn mempool(&self) -> &[(DateTime<Utc>, Transaction)] {
+    pub fn mempool(&self) -> &Mempool {
         &self.mempool
     }
+    /// Outputs paying to `pubkey` that are unspent on the chain or
+    /// created by a mempool transaction, the bool is set for the
+    /// ones on the chain
+    pub fn outputs_of<'a>(
+        &'a self,
+        pubkey: &'a PublicKey,
+    ) -> impl Iterator<Item = (OutPoint, &'a TransactionOutput, bool)> + 'a {
+        let confirmed = self
+            .utxos
+            .iter()
+            .map(|(outpoint, output)| (*outpoint, output, true));
+        let unconfirmed = self
+            .mempool
+            .iter()
+            .flat_map(|entry| entry.transaction.outpoints())
+            .map(|(outpoint, output)| (outpoint, output, false));
+        confirmed
+            .chain(unconfirmed)
+            .filter(move |(_, output, _)| output.pubkey == *pubkey)
+    }
 
     pub fn block_height(&self) -> u64 {
         self.blocks.len() as u64
     }
 
+    /// Reward (in satoshis) for the next block to be mined
+    pub fn calculate_block_reward(&self) -> u64 {
+        crate::INITIAL_REWARD * 10u64.pow(8)
+            / 2u64.pow((self.block_height() / crate::HALVING_INTERVAL) as u32)
+    }
+
+    /// Cumulative work of the active chain
+    pub fn chain_work(&self) -> U256 {
+        self.blocks
+            .last()
+            .and_then(|block| self.chain_work.get(&block.hash()))
+            .copied()
+            .unwrap_or_default()
+    }
+
     /// Rebuild utxo set from blockchain
     pub fn rebuild_utoxs(&mut self) {
+        self.utxos.clear();
+        self.undo.clear();
         for block in &self.blocks {
-            for transaction in &block.transactions {
-                for input in &transaction.inputs {
-                    self.utxos.remove(&input.pre_transaction_output_hash);
-                }
-                for output in transaction.outputs.iter() {
-                    // this will have hash collisions
-                    self.utxos
-                        .insert(transaction.hash(), (false, output.clone()));
+            self.undo.push(Self::apply_block(&mut self.utxos, block));
+        }
+    }
+
+    /// Spend the outputs a block's transactions use as inputs and
+    /// add the outputs they create, returning what was spent
+    fn apply_block(utxos: &mut HashMap<OutPoint, TransactionOutput>, block: &Block) -> BlockUndo {
+        let mut undo = BlockUndo::default();
+        for transaction in &block.transactions {
+            for input in &transaction.inputs {
+                if let Some(output) = utxos.remove(&input.prev_output) {
+                    undo.spent.push((input.prev_output, output));
                 }
             }
+            for (outpoint, output) in transaction.outpoints() {
+                utxos.insert(outpoint, output.clone());
+            }
         }
+        undo
     }
 
-    pub fn add_block(&mut self, block: Block) -> Result<()> {
-        if self.blocks.is_empty() {
-            if block.header.prev_block_hash != Hash::zero() {
-                println!("zero hash");
-                return Err(BtcError::InvalidBlock);
+    /// Undo `apply_block`
+    fn revert_block(
+        utxos: &mut HashMap<OutPoint, TransactionOutput>,
+        block: &Block,
+        undo: BlockUndo,
+    ) {
+        // restore first, so outputs created and spent within
+        // the block end up removed
+        for (outpoint, output) in undo.spent {
+            utxos.insert(outpoint, output);
+        }
+        for transaction in &block.transactions {
+            for (outpoint, _) in transaction.outpoints() {
+                utxos.remove(&outpoint);
             }
+        }
+    }
+
+    /// Drop the `mined` transactions from the mempool, their
+    /// descendants stay, then the transactions spending outputs
+    /// that are no longer unspent together with their descendants
+    fn prune_mempool(&mut self, mined: &[Transaction]) {
+        for transaction in mined {
+            self.mempool.remove(&transaction.hash());
+        }
+        let invalid: Vec<Hash> = self
+            .mempool
+            .iter()
+            .filter(|entry| {
+                entry
+                    .transaction
+                    .inputs
+                    .iter()
+                    .any(|input| self.unspent_output(&input.prev_output).is_none())
+            })
+            .map(|entry| entry.transaction.hash())
+            .collect();
+        for txid in invalid {
+            self.mempool.remove_with_descendants(&txid);
+        }
+    }
+
+    /// An output on the chain or created by a mempool
+    /// transaction that is not spent on the chain
+    fn unspent_output(&self, outpoint: &OutPoint) -> Option<&TransactionOutput> {
+        self.utxos
+            .get(outpoint)
+            .or_else(|| self.mempool.output(outpoint))
+    }
+
+    /// Drop mempool transactions that have waited longer than
+    /// `MAX_MEMPOOL_TRANSACTION_AGE`, so the outputs they spend
+    /// can be spent again
+    pub fn cleanup_mempool(&mut self) {
+        let now = Utc::now();
+        let max_age = chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64);
+        self.mempool.retain(|entry| now - entry.time <= max_age);
+    }
+
+    /// Rebuild the cumulative work of every known block
+    /// and the heights of the active chain
+    fn rebuild_chain_work(&mut self) {
+        self.chain_work.clear();
+        self.heights.clear();
+        let mut work = U256::zero();
+        for (height, block) in self.blocks.iter().enumerate() {
+            work += block.header.work();
+            self.chain_work.insert(block.hash(), work);
+            self.heights.insert(block.hash(), height);
+        }
+        // a side block can only be indexed after its parent
+        let mut pending: Vec<&Block> = self.side_blocks.values().collect();
+        loop {
+            let before = pending.len();
+            pending.retain(|block| {
+                let prev_block_hash = block.header.prev_block_hash;
+                let parent_work = if prev_block_hash == Hash::zero() {
+                    Some(U256::zero())
+                } else {
+                    self.chain_work.get(&prev_block_hash).copied()
+                };
+                match parent_work {
+                    Some(parent_work) => {
+                        self.chain_work
+                            .insert(block.hash(), parent_work + block.header.work());
+                        false
+                    }
+                    None => true,
+                }
+            });
+            if pending.len() == before {
+                break;
+            }
+        }
+    }
+
+    /// Find a block on the active chain or on a side branch
+    pub fn find_block(&self, hash: &Hash) -> Option<&Block> {
+        self.side_blocks
+            .get(hash)
+            .or_else(|| self.heights.get(hash).map(|height| &self.blocks[*height]))
+    }
+
+    /// Headers of the known block `hash` and all its
+    /// ancestors, genesis first
+    fn branch_headers(&self, hash: &Hash) -> Option<Vec<&BlockHeader>> {
+        let mut side = vec![];
+        let mut current = *hash;
+        while let Some(block) = self.side_blocks.get(&current) {
+            side.push(&block.header);
+            current = block.header.prev_block_hash;
+        }
+        let height = if current == Hash::zero() {
+            0
         } else {
-            let last_block = self.blocks.last().unwrap();
-            if block.header.prev_block_hash != last_block.hash() {
-                println!("prev hash is wrong");
-                return Err(BtcError::InvalidBlock);
+            self.heights.get(&current)? + 1
+        };
+        let mut headers: Vec<&BlockHeader> = self.blocks[..height]
+            .iter()
+            .map(|block| &block.header)
+            .collect();
+        headers.extend(side.into_iter().rev());
+        Some(headers)
+    }
+
+    /// Block locator for GetHeaders: hashes of the active chain from
+    /// the tip back, one by one for the last ten blocks, then in
+    /// doubling steps, always ending with the genesis block
+    pub fn locator(&self) -> Vec<Hash> {
+        let mut locator = vec![];
+        let Some(mut height) = self.blocks.len().checked_sub(1) else {
+            return locator;
+        };
+        let mut step = 1;
+        loop {
+            locator.push(self.blocks[height].hash());
+            if height == 0 {
+                return locator;
             }
-            if !block.header.hash().matches_target(block.header.target) {
-                return Err(BtcError::InvalidBlock);
+            if locator.len() >= 10 {
+                step *= 2;
             }
+            height = height.saturating_sub(step);
+        }
+    }
+
+    /// Up to `max` headers of the active chain following the first
+    /// block of `locator` that is on it, from genesis if none is
+    pub fn headers_after(&self, locator: &[Hash], max: usize) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.heights.get(hash))
+            .map_or(0, |height| height + 1);
+        self.blocks
+            .iter()
+            .skip(start)
+            .take(max)
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    /// Check a branch of headers without their blocks: the first
+    /// extends a known block, or starts a new chain, the others are
+    /// checked by `HeaderCheck::extend`. Returns the cumulative work
+    /// of the branch, worth downloading only if it beats `chain_work`
+    pub fn check_headers(&self, headers: &[BlockHeader]) -> Result<U256> {
+        let parent = headers
+            .first()
+            .map_or(Hash::zero(), |header| header.prev_block_hash);
+        let mut check = self
+            .header_check(&parent)
+            .ok_or(BtcError::InvalidBlockHeader)?;
+        check.extend(headers)?;
+        Ok(check.work())
+    }
 
-            let calculated_merkle_root = MerkleRoot::calculate(&block.transactions);
-            if calculated_merkle_root != block.header.merkle_root {
-                return Err(BtcError::InvalidMerkleRoot);
+    /// Start checking headers following the known block `parent`, or
+    /// starting a new chain if it is zero. None if `parent` is unknown
+    pub fn header_check(&self, parent: &Hash) -> Option<HeaderCheck> {
+        let branch = self.branch_headers(parent)?;
+        let height = branch.len() as u64;
+        // the first block of the difficulty period the last one is in
+        let period_start = match height.checked_sub(1) {
+            Some(last) => {
+                let first = last - last % crate::DIFFICULTY_UPDATE_INTERVAL;
+                branch[first as usize].timestamp
             }
-            // check if the block's timestamp is after the
-            // last block's timestamp
-            if block.header.timestamp <= last_block.header.timestamp {
+            None => 0,
+        };
+        Some(HeaderCheck {
+            height,
+            last: branch.last().map(|header| (*header).clone()),
+            last_hash: *parent,
+            period_start,
+            work: self.chain_work.get(parent).copied().unwrap_or_default(),
+        })
+    }
+
+    /// Add a block to the block tree. A block extending the active
+    /// chain is connected, a block on another branch is kept and
+    /// triggers a reorganization once its branch has the most work,
+    /// and a block with an unknown parent waits as an orphan
+    pub fn add_block(&mut self, block: Block) -> Result<AddedBlock> {
+        let hash = block.hash();
+        let mut added = AddedBlock::default();
+        if !self.accept_block(hash, block, &mut added.reorgs)? {
+            added.orphan = self.orphans.contains_key(&hash);
+            return Ok(added);
+        }
+        added.accepted = true;
+        // orphans waiting for this block can be added now
+        let mut parents = vec![hash];
+        while let Some(parent) = parents.pop() {
+            let children: Vec<Hash> = self
+                .orphans
+                .iter()
+                .filter(|(_, block)| block.header.prev_block_hash == parent)
+                .map(|(hash, _)| *hash)
+                .collect();
+            for child in children {
+                let block = self.orphans.remove(&child).expect("BUG: impossible");
+                self.orphan_order.retain(|hash| *hash != child);
+                match self.accept_block(child, block, &mut added.reorgs) {
+                    Ok(true) => parents.push(child),
+                    Ok(false) => {}
+                    Err(e) => added.rejected_orphans.push((child, e)),
+                }
+            }
+        }
+        Ok(added)
+    }
+
+    /// Remember that the block `hash` failed with `error`. A merkle
+    /// root mismatch only says the transactions we got are not the
+    /// ones the header commits to, the real block may still be valid
+    fn mark_invalid(&mut self, hash: Hash, error: &BtcError) {
+        if !matches!(error, BtcError::InvalidMerkleRoot) {
+            self.invalid.insert(hash);
+        }
+    }
+
+    /// Returns true if the block made it into the block tree, a
+    /// reorganization it causes is added to `reorgs`
+    fn accept_block(&mut self, hash: Hash, block: Block, reorgs: &mut Vec<Reorg>) -> Result<bool> {
+        if self.chain_work.contains_key(&hash) || self.orphans.contains_key(&hash) {
+            // already known
+            return Ok(false);
+        }
+        let prev_block_hash = block.header.prev_block_hash;
+        if self.invalid.contains(&hash) || self.invalid.contains(&prev_block_hash) {
+            self.invalid.insert(hash);
+            return Err(BtcError::InvalidBlock);
+        }
+        let extends_tip = match self.blocks.last() {
+            Some(last_block) => last_block.hash() == prev_block_hash,
+            None => prev_block_hash == Hash::zero(),
+        };
+        if extends_tip {
+            if let Err(e) = self.connect_block(block) {
+                self.mark_invalid(hash, &e);
+                return Err(e);
+            }
+            return Ok(true);
+        }
+
+        // a genesis block other than ours starts a competing chain
+        let parent = match self.find_block(&prev_block_hash) {
+            Some(parent) => Some(&parent.header),
+            None if prev_block_hash == Hash::zero() => None,
+            None => {
+                // nothing else can be checked without the parent, the
+                // proof of work at least makes orphans costly to forge
+                if !hash.matches_target(crate::MIN_TARGET) {
+                    return Err(BtcError::InvalidProofOfWork);
+                }
+                if self.orphans.len() >= crate::MAX_ORPHAN_BLOCKS {
+                    let evicted = self.orphan_order.pop_front().expect("BUG: impossible");
+                    self.orphans.remove(&evicted);
+                }
+                self.orphans.insert(hash, block);
+                self.orphan_order.push_back(hash);
+                return Ok(false);
+            }
+        };
+        // the transactions can only be verified once the branch
+        // becomes active
+        let target = self
+            .header_check(&prev_block_hash)
+            .ok_or(BtcError::InvalidBlock)?
+            .next_target();
+        let checked = Self::check_block_header(&block, parent, target);
+        if let Err(e) = checked {
+            self.mark_invalid(hash, &e);
+            return Err(e);
+        }
+        let parent_work = self
+            .chain_work
+            .get(&prev_block_hash)
+            .copied()
+            .unwrap_or_default();
+        let work = parent_work + block.header.work();
+        self.chain_work.insert(hash, work);
+        self.side_blocks.insert(hash, block);
+        if work > self.chain_work() {
+            reorgs.push(self.reorganize(hash)?);
+        }
+        Ok(true)
+    }
+
+    /// Checks that only need the header, its parent's header (none
+    /// for a genesis block) and the target the header has to have
+    fn check_header(
+        header: &BlockHeader,
+        parent: Option<&BlockHeader>,
+        target: U256,
+    ) -> Result<()> {
+        if header.target != target {
+            return Err(BtcError::InvalidBlockHeader);
+        }
+        if !header.hash().matches_target(header.target) {
+            return Err(BtcError::InvalidProofOfWork);
+        }
+        // the block's timestamp can not be before the
+        // parent block's timestamp, with second precision
+        // several blocks may share one
+        if parent.is_some_and(|parent| header.timestamp < parent.timestamp) {
+            return Err(BtcError::InvalidBlock);
+        }
+        Ok(())
+    }
+
+    /// `check_header` and the checks that only need the block itself
+    fn check_block_header(block: &Block, parent: Option<&BlockHeader>, target: U256) -> Result<()> {
+        Self::check_header(&block.header, parent, target)?;
+        let calculated_merkle_root = MerkleRoot::calculate(&block.transactions);
+        if calculated_merkle_root != block.header.merkle_root {
+            return Err(BtcError::InvalidMerkleRoot);
+        }
+        Ok(())
+    }
+
+    /// Append a block to the active chain
+    fn connect_block(&mut self, block: Block) -> Result<()> {
+        if let Some(last_block) = self.blocks.last() {
+            if block.header.prev_block_hash != last_block.hash() {
                 return Err(BtcError::InvalidBlock);
             }
+            Self::check_block_header(&block, Some(&last_block.header), self.target)?;
             // Verify all transactions in the block
             // fails if any transaction fails
             block.verify_transactions(self.block_height(), &self.utxos)?
+        } else if block.header.prev_block_hash != Hash::zero() {
+            return Err(BtcError::InvalidBlock);
+        } else {
+            Self::check_block_header(&block, None, self.target)?;
         }
 
-        // remove transactions from mempool
-        let block_transactions: HashSet<_> =
-            block.transactions.iter().map(|tx| tx.hash()).collect();
+        let undo = Self::apply_block(&mut self.utxos, &block);
+        // remove the block's transactions and the ones conflicting
+        // with them from mempool
+        self.prune_mempool(&block.transactions);
 
-        self.mempool
-            .retain(|(_, tx)| !block_transactions.contains(&tx.hash()));
-
-        //
-        self.try_adjust_target();
+        let work = self.chain_work() + block.header.work();
+        self.chain_work.insert(block.hash(), work);
+        self.heights.insert(block.hash(), self.blocks.len());
+        // the new block has to be on the chain before the
+        // target can be adjusted
         self.blocks.push(block);
+        self.undo.push(undo);
+        self.try_adjust_target();
         Ok(())
     }
 
-    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
-        // validate transaction before insert
-        let mut known_inputs: HashSet<Hash> = HashSet::new();
+    /// Take the last block off the active chain
+    fn disconnect_block(&mut self) -> Option<Block> {
+        let block = self.blocks.pop()?;
+        self.heights.remove(&block.hash());
+        let undo = self.undo.pop().expect("BUG: missing undo data");
+        Self::revert_block(&mut self.utxos, &block, undo);
+        self.recalculate_target();
+        // mempool transactions may spend outputs of the block
+        self.prune_mempool(&[]);
+        Some(block)
+    }
 
-        // check if any of the utxos have the bool mark set to true
-        // and if so, find the transaction that references them
-        // in mempool, remove it, and set all the utxos it references
-        // to false
+    /// Disconnect blocks until the active chain is `height` blocks long
+    fn disconnect_to(&mut self, height: usize) -> Vec<Block> {
+        let mut disconnected = vec![];
+        while self.blocks.len() > height {
+            disconnected.push(self.disconnect_block().expect("BUG: impossible"));
+        }
+        disconnected.reverse();
+        disconnected
+    }
 
-        for input in &transaction.inputs {
-            if !self.utxos.contains_key(&input.pre_transaction_output_hash) {
-                return Err(BtcError::InvalidTransaction);
+    /// Switch the active chain to the branch ending in `new_tip`:
+    /// disconnect the active blocks down to the fork point, connect
+    /// the branch and return the transactions of the disconnected
+    /// blocks to the mempool
+    fn reorganize(&mut self, new_tip: Hash) -> Result<Reorg> {
+        // walk back from the new tip to the active chain
+        let mut branch = vec![];
+        let mut fork_point = new_tip;
+        while let Some(block) = self.side_blocks.remove(&fork_point) {
+            fork_point = block.header.prev_block_hash;
+            branch.push(block);
+        }
+        branch.reverse();
+        let fork_height = if fork_point == Hash::zero() {
+            0
+        } else {
+            self.heights[&fork_point] + 1
+        };
+
+        let old_work = self.chain_work();
+        let disconnected = self.disconnect_to(fork_height);
+        let mut reorg = Reorg {
+            height: fork_height,
+            disconnected: disconnected.len(),
+            connected: branch.len(),
+            dropped: 0,
+        };
+
+        let mut result = Ok(());
+        let mut branch = branch.into_iter();
+        while let Some(block) = branch.next() {
+            let hash = block.hash();
+            if let Err(e) = self.connect_block(block) {
+                // the block and everything built on it is invalid
+                let mut failed = vec![hash];
+                failed.extend(branch.by_ref().map(|block| block.hash()));
+                for hash in failed {
+                    self.chain_work.remove(&hash);
+                    self.invalid.insert(hash);
+                    self.invalidate_descendants(hash);
+                }
+                result = Err(e);
+                break;
             }
-            if known_inputs.contains(&input.pre_transaction_output_hash) {
-                return Err(BtcError::InvalidTransaction);
+        }
+
+        reorg.dropped = if self.chain_work() <= old_work {
+            // the valid part of the branch does not have more work,
+            // go back to the old chain
+            let reverted = self.disconnect_to(fork_height);
+            for block in disconnected {
+                self.connect_block(block)
+                    .expect("BUG: block of the old chain no longer connects");
+            }
+            self.move_to_side_branch(reverted)
+        } else {
+            self.move_to_side_branch(disconnected)
+        };
+        result.map(|()| reorg)
+    }
+
+    /// Drop the side blocks built on the invalid block `hash`
+    /// and remember them as invalid too
+    fn invalidate_descendants(&mut self, hash: Hash) {
+        let mut parents = vec![hash];
+        while let Some(parent) = parents.pop() {
+            let children: Vec<Hash> = self
+                .side_blocks
+                .iter()
+                .filter(|(_, block)| block.header.prev_block_hash == parent)
+                .map(|(hash, _)| *hash)
+                .collect();
+            for child in children {
+                self.side_blocks.remove(&child);
+                self.chain_work.remove(&child);
+                self.invalid.insert(child);
+                parents.push(child);
             }
-            known_inputs.insert(input.pre_transaction_output_hash);
         }
+    }
 
-        for input in &transaction.inputs {
-            if let Some((true, _)) = self.utxos.get(&input.pre_transaction_output_hash) {
-                // find the trnaaction that references thi utxo
-                let referencing_transaction =
-                    self.mempool
-                        .iter()
-                        .enumerate()
-                        .find(|(_, (_, transaction))| {
-                            transaction
-                                .outputs
-                                .iter()
-                                .any(|output| output.hash() == input.pre_transaction_output_hash)
-                        });
-                if let Some((idx, (_, referencing_transaction))) = referencing_transaction {
-                    for input in &referencing_transaction.inputs {
-                        self.utxos
-                            .entry(input.pre_transaction_output_hash)
-                            .and_modify(|(marked, _)| {
-                                *marked = false;
-                            });
-                    }
-                    self.mempool.remove(idx);
-                } else {
-                    self.utxos
-                        .entry(input.pre_transaction_output_hash)
-                        .and_modify(|(marked, _)| {
-                            *marked = false;
-                        });
+    /// Keep blocks that left the active chain as a side branch and
+    /// give their transactions back to the mempool. They were mined
+    /// once, so only the consensus rules apply to them: they replace
+    /// what conflicts with them whatever it pays, and may pay less than
+    /// the relay fee. Returns how many were dropped, because they
+    /// conflict with the active chain or the mempool is full
+    fn move_to_side_branch(&mut self, blocks: Vec<Block>) -> usize {
+        let mut dropped = 0;
+        for block in blocks {
+            for transaction in block.transactions.iter().skip(1) {
+                if self.return_to_mempool(transaction.clone()).is_err() {
+                    dropped += 1;
                 }
             }
+            self.side_blocks.insert(block.hash(), block);
         }
+        dropped
+    }
 
-        let all_inputs: u64 = transaction
-            .inputs
-            .iter()
-            .map(|input| {
-                self.utxos
-                    .get(&input.pre_transaction_output_hash)
-                    .expect("BUG: impossible")
-                    .1
-                    .value
-            })
-            .sum();
+    /// Put a transaction of a disconnected block back in the mempool
+    fn return_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
+        let fee = self.check_transaction(&transaction)?;
+        let txid = transaction.hash();
+        let removed = self.mempool.insert(MempoolEntry::new(transaction, fee));
+        if removed.iter().any(|entry| entry.transaction.hash() == txid) {
+            return Err(BtcError::MempoolFull);
+        }
+        Ok(())
+    }
 
-        let all_outputs = transaction
-            .outputs
-            .iter()
-            .map(|output| output.value)
-            .sum::<u64>();
+    /// Validate a transaction and add it to the mempool. Mempool
+    /// transactions spending the same outputs are replaced if the
+    /// new one pays enough more, see `Mempool::check_replacement`
+    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
+        self.add_to_mempool_at(transaction, Utc::now())
+    }
 
-        if all_inputs < all_outputs {
+    /// The checks a transaction has to pass to be mined on top of the
+    /// active chain and the mempool, returns the fee it pays
+    fn check_transaction(&self, transaction: &Transaction) -> Result<u64> {
+        // coinbase transactions only come with a block
+        if transaction.coinbase_height.is_some() {
             return Err(BtcError::InvalidTransaction);
         }
-        // mark the utxos as used
+        let mut known_inputs: HashSet<OutPoint> = HashSet::new();
+        for (index, input) in transaction.inputs.iter().enumerate() {
+            // may well be honest, the parent could still be on its way
+            // or the output was spent by a block the sender has not seen
+            let Some(prev_output) = self.unspent_output(&input.prev_output) else {
+                return Err(BtcError::MissingInput);
+            };
+            if !transaction.verify_input(index, prev_output) {
+                return Err(BtcError::InvalidSignature);
+            }
+            if !known_inputs.insert(input.prev_output) {
+                return Err(BtcError::InvalidTransaction);
+            }
+        }
+        self.transaction_fee(transaction)
+            .ok_or(BtcError::InvalidTransaction)
+    }
+
+    /// `add_to_mempool` for a transaction that entered the mempool at `time`
+    fn add_to_mempool_at(&mut self, transaction: Transaction, time: DateTime<Utc>) -> Result<()> {
+        let fee = self.check_transaction(&transaction)?;
+        if fee < crate::MIN_RELAY_FEE_RATE * transaction.size() as u64 {
+            return Err(BtcError::FeeTooLow);
+        }
+
+        let txid = transaction.hash();
+        if self.mempool.contains(&txid) {
+            return Err(BtcError::AlreadyInMempool);
+        }
+        let mut entry = MempoolEntry::new(transaction, fee);
+        entry.time = time;
+        self.mempool.check_replacement(&entry)?;
+        self.mempool.check_limits(&entry.transaction)?;
+        let removed = self.mempool.insert(entry);
+        // the mempool is full of transactions paying better
+        if removed.iter().any(|entry| entry.transaction.hash() == txid) {
+            return Err(BtcError::MempoolFull);
+        }
+        Ok(())
+    }
+
+    /// Take in a mempool saved before a restart: every transaction is
+    /// validated again against the current chain, the ones that are
+    /// no longer valid or have expired are dropped. Returns how many
+    /// were dropped
+    pub fn restore_mempool(&mut self, mempool: Mempool) -> usize {
+        let mut dropped = 0;
+        for entry in mempool.into_entries() {
+            if self
+                .add_to_mempool_at(entry.transaction, entry.time)
+                .is_err()
+            {
+                dropped += 1;
+            }
+        }
+        let before = self.mempool.len();
+        self.cleanup_mempool();
+        dropped + before - self.mempool.len()
+    }
+
+    /// Fee a transaction pays, None if it spends unknown
+    /// outputs or creates more value than it spends
+    fn transaction_fee(&self, transaction: &Transaction) -> Option<u64> {
+        let mut input_value: u64 = 0;
         for input in &transaction.inputs {
-            self.utxos
-                .entry(input.pre_transaction_output_hash)
-                .and_modify(|(marked, _)| {
-                    *marked = true;
-                });
+            let output = self.unspent_output(&input.prev_output)?;
+            input_value = input_value.checked_add(output.value)?;
         }
+        let output_value = transaction
+            .outputs
+            .iter()
+            .try_fold(0u64, |sum, output| sum.checked_add(output.value))?;
+        input_value.checked_sub(output_value)
+    }
 
-        self.mempool.push((Utc::now(), transaction));
-        self.mempool.sort_by_key(|(_, transaction)| {
-            let all_inputs: u64 = transaction
-                .inputs
+    /// A block on top of the active chain, ready to be mined: the
+    /// mempool transactions paying the highest fee rate together with
+    /// their unconfirmed ancestors, parents before children, and a
+    /// coinbase paying the block reward plus those fees to `pubkey`,
+    /// `BLOCK_TRANSACTION_CAP` transactions at most
+    pub fn build_template(&self, pubkey: PublicKey) -> Block {
+        let mut included: HashSet<Hash> = HashSet::new();
+        let mut fees = 0;
+        let mut transactions = vec![];
+        for entry in self.mempool.iter() {
+            let txid = entry.transaction.hash();
+            if included.contains(&txid) {
+                continue;
+            }
+            // a transaction comes with the ancestors not in the block yet
+            let mut package: Vec<&MempoolEntry> = self
+                .mempool
+                .ancestors(&txid)
                 .iter()
-                .map(|input| {
-                    self.utxos
-                        .get(&input.pre_transaction_output_hash)
-                        .expect("")
-                        .1
-                        .value
-                })
-                .sum::<u64>();
-            let all_outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
-            let miner_fee = all_inputs - all_outputs;
-            miner_fee
-        });
-        Ok(())
+                .filter(|ancestor| !included.contains(*ancestor))
+                .filter_map(|ancestor| self.mempool.get(ancestor))
+                .collect();
+            package.push(entry);
+            // the coinbase takes one of the places
+            if 1 + transactions.len() + package.len() > crate::BLOCK_TRANSACTION_CAP {
+                continue;
+            }
+            // parents first, as in `Mempool::into_entries`
+            package.sort_by_key(|entry| entry.ancestors().count);
+            for entry in package {
+                included.insert(entry.transaction.hash());
+                fees += entry.fee;
+                transactions.push(entry.transaction.clone());
+            }
+        }
+
+        let coinbase = Transaction::coinbase(
+            self.block_height(),
+            vec![TransactionOutput {
+                value: self.calculate_block_reward() + fees,
+                pubkey,
+            }],
+        );
+        transactions.insert(0, coinbase);
+
+        let (prev_block_hash, timestamp) = match self.blocks.last() {
+            // a block can not be older than its parent
+            Some(block) => (block.hash(), Utc::now().max(block.header.time())),
+            None => (Hash::zero(), Utc::now()),
+        };
+        Block::new(
+            BlockHeader::new(
+                timestamp,
+                0,
+                prev_block_hash,
+                MerkleRoot::calculate(&transactions),
+                self.target,
+            ),
+            transactions,
+        )
+    }
+
+    /// Derive the target from the active chain after blocks
+    /// have been disconnected
+    fn recalculate_target(&mut self) {
+        self.target = self
+            .blocks
+            .last()
+            .map(|block| block.header.target)
+            .unwrap_or(crate::MIN_TARGET);
+        self.try_adjust_target();
     }
 
     pub fn try_adjust_target(&mut self) {
-        if self.block_height() % crate::DIFFICULTY_UPDATE_INTERVAL != 0 {
+        if self.blocks.is_empty()
+            || !self
+                .block_height()
+                .is_multiple_of(crate::DIFFICULTY_UPDATE_INTERVAL)
+        {
             return; // not time to adjust the target
         }
         // measure the time it took to mine the last crate::DIFFICULTY_UPDATE_INTERVAL blocks
@@ -252,34 +944,801 @@ impl Blockchain {
             .header
             .timestamp;
         let end_time = self.blocks.last().unwrap().header.timestamp;
-        let time_diff = end_time - start_time;
-        let time_diff_sconds: Option<i64> = time_diff.num_nanoseconds();
+        self.target = Self::adjusted_target(self.target, start_time, end_time);
+    }
+
+    /// `target` scaled by how much longer or shorter than ideal the
+    /// blocks mined between `start_time` and `end_time` took
+    fn adjusted_target(target: U256, start_time: u32, end_time: u32) -> U256 {
+        let time_diff_seconds: i64 = end_time as i64 - start_time as i64;
         let target_seconds: u64 = crate::DIFFICULTY_UPDATE_INTERVAL * crate::IDEAL_BLOCK_TIME;
-        let new_target = BigDecimal::parse_bytes(&self.target.to_string().as_bytes(), 10)
+        let new_target = BigDecimal::parse_bytes(target.to_string().as_bytes(), 10)
             .expect("BUG: impossible")
-            * (BigDecimal::from(time_diff_sconds.unwrap()) / BigDecimal::from(target_seconds));
+            * (BigDecimal::from(time_diff_seconds) / BigDecimal::from(target_seconds));
         // clamp new_target to be within the range of
-        // 4 * self.target and self.target / 4
+        // 4 * target and target / 4
         let new_target_str = new_target
             .to_string()
             .split('.')
             .next()
             .expect("BUG: Expected a decimal point")
             .to_owned();
-        let new_target: U256 = U256::from_str_radix(&new_target_str, 10).expect("BUG: impossible");
-        if new_target < self.target / 4 {
-            dbg!(self.target / 4)
-        } else if new_target > self.target * 4 {
-            dbg!(self.target * 4)
+        // anything beyond the clamp range is clamped anyway
+        let new_target: U256 = U256::from_str_radix(&new_target_str, 10).unwrap_or(U256::MAX);
+        let new_target = if new_target < target / 4 {
+            target / 4
+        } else if new_target > target.saturating_mul(4.into()) {
+            target.saturating_mul(4.into())
         } else {
             new_target
         };
 
-        dbg!(new_target);
-
         // if the new target is more than the minimum target,
         // set it to the minimum target
-        self.target = new_target.min(crate::MIN_TARGET);
-        dbg!(self.target);
+        new_target.min(crate::MIN_TARGET)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::types::{SigHash, TransactionInput};
+    use chrono::Duration;
+
+    fn output(value: u64, key: &PrivateKey) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            pubkey: key.public_key(),
+        }
+    }
+
+    /// A transaction spending `spent` to `outputs`, signed with SigHash::ALL
+    fn spend(spent: &[(OutPoint, &PrivateKey)], outputs: Vec<TransactionOutput>) -> Transaction {
+        let prev_outputs: Vec<OutPoint> = spent.iter().map(|(outpoint, _)| *outpoint).collect();
+        let inputs = spent
+            .iter()
+            .enumerate()
+            .map(|(index, (outpoint, key))| {
+                let hash =
+                    Transaction::signature_hash(&prev_outputs, &outputs, index, SigHash::ALL)
+                        .unwrap();
+                TransactionInput {
+                    prev_output: *outpoint,
+                    signature: Signature::sign(&hash, key),
+                    sighash: SigHash::ALL,
+                }
+            })
+            .collect();
+        Transaction::new(inputs, outputs)
+    }
+
+    /// Mine a block on top of the active chain paying reward and fees to `miner`
+    fn mine(
+        blockchain: &Blockchain,
+        miner: &PrivateKey,
+        fees: u64,
+        mut transactions: Vec<Transaction>,
+    ) -> Block {
+        let coinbase = Transaction::coinbase(
+            blockchain.block_height(),
+            vec![output(blockchain.calculate_block_reward() + fees, miner)],
+        );
+        transactions.insert(0, coinbase);
+        let (prev_block_hash, timestamp) = match blockchain.blocks.last() {
+            Some(block) => (block.hash(), block.header.time() + Duration::seconds(1)),
+            None => (Hash::zero(), Utc::now()),
+        };
+        let mut block = Block::new(
+            BlockHeader::new(
+                timestamp,
+                0,
+                prev_block_hash,
+                MerkleRoot::calculate(&transactions),
+                blockchain.target(),
+            ),
+            transactions,
+        );
+        while !block.header.mine(1_000) {}
+        block
+    }
+
+    /// A chain of just a genesis block paying `key`, with that
+    /// block, its coinbase output and the output's value
+    fn funded(key: &PrivateKey) -> (Blockchain, Block, OutPoint, u64) {
+        let mut blockchain = Blockchain::new();
+        let genesis = mine(&blockchain, key, 0, vec![]);
+        blockchain.add_block(genesis.clone()).unwrap();
+        let coinbase = &genesis.transactions[0];
+        let outpoint = OutPoint::new(coinbase.hash(), 0);
+        let value = coinbase.outputs[0].value;
+        (blockchain, genesis, outpoint, value)
+    }
+
+    /// A chain with a spend in every block after genesis; the first
+    /// spend creates two outputs in the same transaction
+    fn build_chain() -> (Blockchain, Vec<Block>) {
+        let alice = PrivateKey::new_key();
+        let bob = PrivateKey::new_key();
+        let mut blockchain = Blockchain::new();
+        let mut blocks = vec![];
+
+        let genesis = mine(&blockchain, &alice, 0, vec![]);
+        blockchain.add_block(genesis.clone()).unwrap();
+        blocks.push(genesis.clone());
+
+        let coinbase = &genesis.transactions[0];
+        let change = coinbase.outputs[0].value - 1_000 - 10;
+        let first = spend(
+            &[(OutPoint::new(coinbase.hash(), 0), &alice)],
+            vec![output(1_000, &bob), output(change, &alice)],
+        );
+        let first_txid = first.hash();
+        let block = mine(&blockchain, &alice, 10, vec![first]);
+        blockchain.add_block(block.clone()).unwrap();
+        blocks.push(block);
+
+        let second = spend(
+            &[
+                (OutPoint::new(first_txid, 0), &bob),
+                (OutPoint::new(first_txid, 1), &alice),
+            ],
+            vec![output(change + 1_000 - 20, &bob)],
+        );
+        let block = mine(&blockchain, &bob, 20, vec![second]);
+        blockchain.add_block(block.clone()).unwrap();
+        blocks.push(block);
+
+        (blockchain, blocks)
+    }
+
+    #[test]
+    fn template_passes_verification() {
+        let alice = PrivateKey::new_key();
+        let miner = PrivateKey::new_key();
+        let (mut blockchain, _, coinbase, value) = funded(&alice);
+
+        // two transactions spending the same output, the one
+        // paying the higher fee replaces the other
+        let cheap = spend(&[(coinbase, &alice)], vec![output(value - 1_000, &alice)]);
+        let expensive = spend(&[(coinbase, &alice)], vec![output(value - 5_000, &alice)]);
+        blockchain.add_to_mempool(cheap).unwrap();
+        blockchain.add_to_mempool(expensive.clone()).unwrap();
+        assert_eq!(blockchain.mempool.len(), 1);
+
+        let template = blockchain.build_template(miner.public_key());
+        assert_eq!(template.transactions.len(), 2);
+        assert_eq!(template.transactions[1].hash(), expensive.hash());
+        assert_eq!(
+            template.transactions[0].outputs[0].value,
+            blockchain.calculate_block_reward() + 5_000
+        );
+        template
+            .verify_transactions(blockchain.block_height(), &blockchain.utxos)
+            .unwrap();
+    }
+
+    #[test]
+    fn template_orders_by_fee_rate() {
+        let alice = PrivateKey::new_key();
+        let miner = PrivateKey::new_key();
+        let mut blockchain = Blockchain::new();
+        let first = mine(&blockchain, &alice, 0, vec![]);
+        blockchain.add_block(first.clone()).unwrap();
+        let second = mine(&blockchain, &alice, 0, vec![]);
+        blockchain.add_block(second.clone()).unwrap();
+        let coinbases: Vec<(OutPoint, u64)> = [first, second]
+            .iter()
+            .map(|block| {
+                let coinbase = &block.transactions[0];
+                (OutPoint::new(coinbase.hash(), 0), coinbase.outputs[0].value)
+            })
+            .collect();
+
+        // the bigger transaction pays more in total but less per byte
+        let (outpoint, value) = coinbases[0];
+        let big = spend(
+            &[(outpoint, &alice)],
+            vec![
+                output(1, &alice),
+                output(1, &alice),
+                output(value - 2 - 300, &alice),
+            ],
+        );
+        let (outpoint, value) = coinbases[1];
+        let small = spend(&[(outpoint, &alice)], vec![output(value - 250, &alice)]);
+        assert!(300 * (small.size() as u64) < 250 * (big.size() as u64));
+        blockchain.add_to_mempool(big.clone()).unwrap();
+        blockchain.add_to_mempool(small.clone()).unwrap();
+
+        let template = blockchain.build_template(miner.public_key());
+        assert_eq!(template.transactions[1].hash(), small.hash());
+        assert_eq!(template.transactions[2].hash(), big.hash());
+    }
+
+    #[test]
+    fn template_counts_the_coinbase_against_the_cap() {
+        let alice = PrivateKey::new_key();
+        let miner = PrivateKey::new_key();
+        let (mut blockchain, _, coinbase, value) = funded(&alice);
+        let count = crate::BLOCK_TRANSACTION_CAP + 5;
+        let share = (value - 10_000) / count as u64;
+        let split = spend(
+            &[(coinbase, &alice)],
+            (0..count).map(|_| output(share, &alice)).collect(),
+        );
+        let outpoints: Vec<OutPoint> = split.outpoints().map(|(outpoint, _)| outpoint).collect();
+        let fee = value - share * count as u64;
+        let block = mine(&blockchain, &alice, fee, vec![split]);
+        blockchain.add_block(block).unwrap();
+        for outpoint in outpoints {
+            let payment = spend(&[(outpoint, &alice)], vec![output(share - 1_000, &alice)]);
+            blockchain.add_to_mempool(payment).unwrap();
+        }
+
+        let template = blockchain.build_template(miner.public_key());
+        assert!(template.transactions.len() <= crate::BLOCK_TRANSACTION_CAP);
+        assert_eq!(template.transactions.len(), crate::BLOCK_TRANSACTION_CAP);
+    }
+
+    #[test]
+    fn rejects_fee_below_minimum_rate() {
+        let alice = PrivateKey::new_key();
+        let (mut blockchain, _, coinbase, value) = funded(&alice);
+
+        let minimum = crate::MIN_RELAY_FEE_RATE * Transaction::size_of(1, 1) as u64;
+        let cheap = spend(
+            &[(coinbase, &alice)],
+            vec![output(value - minimum + 1, &alice)],
+        );
+        assert!(matches!(
+            blockchain.add_to_mempool(cheap),
+            Err(BtcError::FeeTooLow)
+        ));
+        let enough = spend(&[(coinbase, &alice)], vec![output(value - minimum, &alice)]);
+        blockchain.add_to_mempool(enough).unwrap();
+    }
+
+    #[test]
+    fn tells_missing_inputs_from_invalid_ones() {
+        let alice = PrivateKey::new_key();
+        let bob = PrivateKey::new_key();
+        let (mut blockchain, _, coinbase, _) = funded(&alice);
+
+        let unknown = OutPoint::new(Hash::hash_bytes(b"unknown"), 0);
+        assert!(matches!(
+            blockchain.add_to_mempool(spend(&[(unknown, &alice)], vec![output(1, &alice)])),
+            Err(BtcError::MissingInput)
+        ));
+        assert!(matches!(
+            blockchain.add_to_mempool(spend(&[(coinbase, &bob)], vec![output(1, &bob)])),
+            Err(BtcError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_cheaper_conflicting_transaction() {
+        let alice = PrivateKey::new_key();
+        let bob = PrivateKey::new_key();
+        let (mut blockchain, _, coinbase, value) = funded(&alice);
+
+        let first = spend(&[(coinbase, &alice)], vec![output(value - 5_000, &bob)]);
+        blockchain.add_to_mempool(first.clone()).unwrap();
+        assert!(matches!(
+            blockchain.add_to_mempool(first.clone()),
+            Err(BtcError::AlreadyInMempool)
+        ));
+        let cheaper = spend(&[(coinbase, &alice)], vec![output(value - 1_000, &alice)]);
+        assert!(matches!(
+            blockchain.add_to_mempool(cheaper),
+            Err(BtcError::ReplacementFeeRateTooLow)
+        ));
+        assert_eq!(blockchain.mempool.spender(&coinbase), Some(&first.hash()));
+    }
+
+    #[test]
+    fn spends_unconfirmed_change() {
+        let alice = PrivateKey::new_key();
+        let bob = PrivateKey::new_key();
+        let miner = PrivateKey::new_key();
+        let (mut blockchain, _, coinbase, value) = funded(&alice);
+
+        // a cheap parent and a child paying for both
+        let parent = spend(
+            &[(coinbase, &alice)],
+            vec![output(1_000, &bob), output(value - 1_000 - 500, &alice)],
+        );
+        let change = OutPoint::new(parent.hash(), 1);
+        let child = spend(
+            &[(change, &alice)],
+            vec![output(value - 1_000 - 500 - 10_000, &bob)],
+        );
+        blockchain.add_to_mempool(parent.clone()).unwrap();
+        blockchain.add_to_mempool(child.clone()).unwrap();
+        assert!(blockchain
+            .outputs_of(&alice.public_key())
+            .any(|(outpoint, _, confirmed)| outpoint == change && !confirmed));
+
+        let template = blockchain.build_template(miner.public_key());
+        let txids: Vec<Hash> = template
+            .transactions
+            .iter()
+            .map(|transaction| transaction.hash())
+            .collect();
+        assert_eq!(txids[1..], [parent.hash(), child.hash()]);
+        assert_eq!(
+            template.transactions[0].outputs[0].value,
+            blockchain.calculate_block_reward() + 10_500
+        );
+        template
+            .verify_transactions(blockchain.block_height(), &blockchain.utxos)
+            .unwrap();
+
+        // mining only the parent keeps the child in the mempool
+        let block = mine(&blockchain, &miner, 500, vec![parent]);
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.mempool.len(), 1);
+        assert!(blockchain.mempool.contains(&child.hash()));
+    }
+
+    #[test]
+    fn restores_saved_mempool() {
+        let alice = PrivateKey::new_key();
+        let bob = PrivateKey::new_key();
+        let (mut blockchain, genesis, coinbase, value) = funded(&alice);
+        let parent = spend(&[(coinbase, &alice)], vec![output(value - 1_000, &alice)]);
+        let child = spend(
+            &[(OutPoint::new(parent.hash(), 0), &alice)],
+            vec![output(value - 2_000, &bob)],
+        );
+        blockchain.add_to_mempool(parent.clone()).unwrap();
+        blockchain.add_to_mempool(child.clone()).unwrap();
+
+        let mut saved = vec![];
+        blockchain.mempool.save(&mut saved).unwrap();
+        let mempool = Mempool::load(saved.as_slice()).unwrap();
+        assert_eq!(mempool.len(), 2);
+
+        // the same transactions are valid on a fresh copy of the chain
+        let mut restarted = Blockchain::new();
+        restarted.add_block(genesis).unwrap();
+        assert_eq!(restarted.restore_mempool(mempool.clone()), 0);
+        assert!(restarted.mempool.contains(&child.hash()));
+        assert_eq!(
+            restarted.mempool.get(&parent.hash()).unwrap().time,
+            blockchain.mempool.get(&parent.hash()).unwrap().time
+        );
+
+        // once the parent is in a block it is dropped, the child stays
+        let block = mine(&blockchain, &alice, 1_000, vec![parent]);
+        blockchain.add_block(block).unwrap();
+        let mut restarted = blockchain.clone();
+        restarted.mempool = Mempool::default();
+        assert_eq!(restarted.restore_mempool(mempool), 1);
+        assert_eq!(restarted.mempool.len(), 1);
+        assert!(restarted.mempool.contains(&child.hash()));
+    }
+
+    #[test]
+    fn cleanup_removes_expired_transactions() {
+        let alice = PrivateKey::new_key();
+        let (mut blockchain, _, coinbase, value) = funded(&alice);
+        blockchain
+            .add_to_mempool(spend(
+                &[(coinbase, &alice)],
+                vec![output(value - 1_000, &alice)],
+            ))
+            .unwrap();
+        assert!(blockchain.mempool.is_spent(&coinbase));
+
+        blockchain.cleanup_mempool();
+        assert_eq!(blockchain.mempool.len(), 1);
+
+        let age = Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64 + 1);
+        let mut entry = blockchain.mempool.iter().next().unwrap().clone();
+        entry.time = Utc::now() - age;
+        blockchain.mempool.insert(entry);
+        blockchain.cleanup_mempool();
+        assert!(blockchain.mempool.is_empty());
+        assert!(!blockchain.mempool.is_spent(&coinbase));
+    }
+
+    #[test]
+    fn serves_and_checks_headers() {
+        let (blockchain, blocks) = build_chain();
+        let hashes: Vec<Hash> = blocks.iter().rev().map(|block| block.hash()).collect();
+        assert_eq!(blockchain.locator(), hashes);
+
+        let mut behind = Blockchain::new();
+        behind.add_block(blocks[0].clone()).unwrap();
+        let headers = blockchain.headers_after(&behind.locator(), 10);
+        let served: Vec<Hash> = headers.iter().map(|header| header.hash()).collect();
+        assert_eq!(served, vec![blocks[1].hash(), blocks[2].hash()]);
+        // without a known block in the locator from genesis on
+        assert_eq!(blockchain.headers_after(&[], 1)[0].hash(), blocks[0].hash());
+
+        assert_eq!(
+            behind.check_headers(&headers).unwrap(),
+            blockchain.chain_work()
+        );
+    }
+
+    #[test]
+    fn rejects_headers_breaking_the_rules() {
+        let (blockchain, blocks) = build_chain();
+        let mut behind = Blockchain::new();
+        behind.add_block(blocks[0].clone()).unwrap();
+        let headers = blockchain.headers_after(&behind.locator(), 10);
+
+        // an easier target than the rules give
+        let mut easy = headers.clone();
+        easy[0].target = U256::MAX;
+        while !easy[0].mine(1_000) {}
+        assert!(matches!(
+            behind.check_headers(&easy),
+            Err(BtcError::InvalidBlockHeader)
+        ));
+        let mut block = blocks[1].clone();
+        block.header = easy[0].clone();
+        assert!(matches!(
+            behind.add_block(block),
+            Err(BtcError::InvalidBlockHeader)
+        ));
+        // headers not extending a known block
+        assert!(matches!(
+            behind.check_headers(&headers[1..]),
+            Err(BtcError::InvalidBlockHeader)
+        ));
+    }
+
+    #[test]
+    fn checks_headers_a_batch_at_a_time() {
+        // one block past the first target adjustment
+        let mut blockchain = Blockchain::new();
+        for _ in 0..=crate::DIFFICULTY_UPDATE_INTERVAL {
+            let block = mine(&blockchain, &PrivateKey::new_key(), 0, vec![]);
+            blockchain.add_block(block).unwrap();
+        }
+        let headers = blockchain.headers_after(&[], usize::MAX);
+        assert_ne!(headers[0].target, headers.last().unwrap().target);
+
+        let behind = Blockchain::new();
+        let mut check = behind.header_check(&Hash::zero()).unwrap();
+        for batch in headers.chunks(7) {
+            check.extend(batch).unwrap();
+        }
+        assert_eq!(check.height(), blockchain.block_height());
+        assert_eq!(check.work(), blockchain.chain_work());
+        assert_eq!(
+            behind.check_headers(&headers).unwrap(),
+            blockchain.chain_work()
+        );
+
+        // picking up in the middle of a difficulty period
+        let mut partial = Blockchain::new();
+        for block in blockchain.blocks().take(30) {
+            partial.add_block(block.clone()).unwrap();
+        }
+        let mut check = partial.header_check(&headers[29].hash()).unwrap();
+        check.extend(&headers[30..]).unwrap();
+        assert_eq!(check.work(), blockchain.chain_work());
+        // the adjusted target is enforced
+        let mut easy = headers[crate::DIFFICULTY_UPDATE_INTERVAL as usize].clone();
+        easy.target = headers[0].target;
+        let mut check = partial.header_check(&headers[29].hash()).unwrap();
+        check.extend(&headers[30..50]).unwrap();
+        assert!(matches!(
+            check.extend(&[easy]),
+            Err(BtcError::InvalidBlockHeader)
+        ));
+    }
+
+    #[test]
+    fn connect_updates_utxos() {
+        let (blockchain, blocks) = build_chain();
+        // two coinbases and the output of the last spend
+        assert_eq!(blockchain.utxos.len(), 3);
+        for block in &blocks[1..] {
+            let coinbase = OutPoint::new(block.transactions[0].hash(), 0);
+            assert!(blockchain.utxos.contains_key(&coinbase));
+        }
+        let last = OutPoint::new(blocks[2].transactions[1].hash(), 0);
+        assert!(blockchain.utxos.contains_key(&last));
+        // both outputs of the first spend were spent again
+        assert!(blocks[1].transactions[1]
+            .outpoints()
+            .all(|(outpoint, _)| !blockchain.utxos.contains_key(&outpoint)));
+    }
+
+    #[test]
+    fn rebuild_matches_incremental() {
+        let (blockchain, _) = build_chain();
+        let mut rebuilt = blockchain.clone();
+        rebuilt.rebuild_utoxs();
+        assert_eq!(rebuilt.utxos, blockchain.utxos);
+    }
+
+    #[test]
+    fn disconnect_restores_utxos() {
+        let (mut blockchain, blocks) = build_chain();
+        // utxo set after each prefix of the chain
+        let mut expected = vec![];
+        for height in 0..=blocks.len() {
+            let mut prefix = Blockchain::new();
+            prefix.blocks = blocks[..height].to_vec();
+            prefix.rebuild_utoxs();
+            expected.push(prefix.utxos);
+        }
+        for height in (0..blocks.len()).rev() {
+            let block = blockchain.disconnect_block().unwrap();
+            assert_eq!(block.hash(), blocks[height].hash());
+            assert_eq!(blockchain.utxos, expected[height]);
+        }
+        assert!(blockchain.utxos.is_empty());
+    }
+
+    /// A chain with just a genesis block and a copy of it to mine a
+    /// competing branch on
+    fn fork() -> (Blockchain, Blockchain) {
+        let (blockchain, _, _, _) = funded(&PrivateKey::new_key());
+        let fork = blockchain.clone();
+        (blockchain, fork)
+    }
+
+    fn tip(blockchain: &Blockchain) -> Hash {
+        blockchain.blocks.last().unwrap().hash()
+    }
+
+    #[test]
+    fn keeps_first_branch_with_equal_work() {
+        let (mut blockchain, mut fork) = fork();
+        let active = mine(&blockchain, &PrivateKey::new_key(), 0, vec![]);
+        blockchain.add_block(active.clone()).unwrap();
+        let side = mine(&fork, &PrivateKey::new_key(), 0, vec![]);
+        fork.add_block(side.clone()).unwrap();
+
+        blockchain.add_block(side.clone()).unwrap();
+        assert_eq!(tip(&blockchain), active.hash());
+        assert!(blockchain.side_blocks.contains_key(&side.hash()));
+        assert_eq!(blockchain.chain_work(), fork.chain_work());
+    }
+
+    #[test]
+    fn reorganizes_to_branch_with_more_work() {
+        let (mut blockchain, mut fork) = fork();
+        let active = mine(&blockchain, &PrivateKey::new_key(), 0, vec![]);
+        blockchain.add_block(active.clone()).unwrap();
+        let mut reorgs = vec![];
+        for _ in 0..2 {
+            let block = mine(&fork, &PrivateKey::new_key(), 0, vec![]);
+            fork.add_block(block.clone()).unwrap();
+            let added = blockchain.add_block(block).unwrap();
+            assert!(added.accepted);
+            reorgs.extend(added.reorgs);
+        }
+
+        assert_eq!(reorgs.len(), 1);
+        assert_eq!(
+            (
+                reorgs[0].height,
+                reorgs[0].disconnected,
+                reorgs[0].connected
+            ),
+            (1, 1, 2)
+        );
+        assert_eq!(tip(&blockchain), tip(&fork));
+        assert_eq!(blockchain.block_height(), 3);
+        assert_eq!(blockchain.chain_work(), fork.chain_work());
+        assert_eq!(blockchain.utxos, fork.utxos);
+        assert!(blockchain.side_blocks.contains_key(&active.hash()));
+    }
+
+    #[test]
+    fn connects_orphans_when_parent_arrives() {
+        let (mut blockchain, mut ahead) = fork();
+        let mut blocks = vec![];
+        for _ in 0..3 {
+            let block = mine(&ahead, &PrivateKey::new_key(), 0, vec![]);
+            ahead.add_block(block.clone()).unwrap();
+            blocks.push(block);
+        }
+
+        for block in blocks[1..].iter().rev() {
+            let added = blockchain.add_block(block.clone()).unwrap();
+            assert!(added.orphan && !added.accepted);
+        }
+        assert_eq!(blockchain.block_height(), 1);
+        assert_eq!(blockchain.orphans.len(), 2);
+        blockchain.add_block(blocks[0].clone()).unwrap();
+        assert_eq!(blockchain.block_height(), 4);
+        assert!(blockchain.orphans.is_empty());
+        assert_eq!(tip(&blockchain), tip(&ahead));
+    }
+
+    #[test]
+    fn keeps_the_newest_orphans_with_proof_of_work() {
+        let mut blockchain = Blockchain::new();
+        let empty = Blockchain::new();
+        let orphan = |parent: &[u8]| {
+            let mut block = mine(&empty, &PrivateKey::new_key(), 0, vec![]);
+            block.header.prev_block_hash = Hash::hash_bytes(parent);
+            while !block.header.mine(1_000) {}
+            block
+        };
+
+        let mut unmined = orphan(b"unknown");
+        unmined.header.target = U256::MAX;
+        while unmined.hash().matches_target(crate::MIN_TARGET) {
+            unmined.header.nonce += 1;
+        }
+        assert!(matches!(
+            blockchain.add_block(unmined),
+            Err(BtcError::InvalidProofOfWork)
+        ));
+        assert!(blockchain.orphans.is_empty());
+
+        let oldest = orphan(b"oldest");
+        blockchain.add_block(oldest.clone()).unwrap();
+        // fill the pool up without mining a hundred blocks
+        for index in 1..crate::MAX_ORPHAN_BLOCKS as u64 {
+            let hash = Hash::hash_bytes(&index.to_be_bytes());
+            blockchain.orphans.insert(hash, oldest.clone());
+            blockchain.orphan_order.push_back(hash);
+        }
+        let newest = orphan(b"newest");
+        blockchain.add_block(newest.clone()).unwrap();
+        assert_eq!(blockchain.orphans.len(), crate::MAX_ORPHAN_BLOCKS);
+        assert!(!blockchain.orphans.contains_key(&oldest.hash()));
+        assert!(blockchain.orphans.contains_key(&newest.hash()));
+    }
+
+    #[test]
+    fn returns_disconnected_transactions_to_mempool() {
+        let alice = PrivateKey::new_key();
+        let (mut blockchain, _, coinbase, value) = funded(&alice);
+        let mut fork = blockchain.clone();
+
+        let payment = spend(&[(coinbase, &alice)], vec![output(value - 1_000, &alice)]);
+        let block = mine(&blockchain, &alice, 1_000, vec![payment.clone()]);
+        blockchain.add_block(block).unwrap();
+        assert!(blockchain.mempool.is_empty());
+
+        for _ in 0..2 {
+            let block = mine(&fork, &PrivateKey::new_key(), 0, vec![]);
+            fork.add_block(block.clone()).unwrap();
+            blockchain.add_block(block).unwrap();
+        }
+        assert_eq!(tip(&blockchain), tip(&fork));
+        assert_eq!(blockchain.mempool.len(), 1);
+        assert!(blockchain
+            .mempool
+            .iter()
+            .any(|entry| entry.transaction.hash() == payment.hash()));
+    }
+
+    #[test]
+    fn returns_transactions_below_the_relay_fee() {
+        let alice = PrivateKey::new_key();
+        let (mut blockchain, _, coinbase, value) = funded(&alice);
+        let mut fork = blockchain.clone();
+
+        let free = spend(&[(coinbase, &alice)], vec![output(value, &alice)]);
+        assert!(matches!(
+            blockchain.clone().add_to_mempool(free.clone()),
+            Err(BtcError::FeeTooLow)
+        ));
+        let block = mine(&blockchain, &alice, 0, vec![free.clone()]);
+        blockchain.add_block(block).unwrap();
+
+        for _ in 0..2 {
+            let block = mine(&fork, &PrivateKey::new_key(), 0, vec![]);
+            fork.add_block(block.clone()).unwrap();
+            blockchain.add_block(block).unwrap();
+        }
+        assert_eq!(tip(&blockchain), tip(&fork));
+        assert!(blockchain.mempool.contains(&free.hash()));
+    }
+
+    #[test]
+    fn rolls_back_invalid_branch() {
+        let (mut blockchain, mut fork) = fork();
+        let active = mine(&blockchain, &PrivateKey::new_key(), 0, vec![]);
+        blockchain.add_block(active.clone()).unwrap();
+        let valid = mine(&fork, &PrivateKey::new_key(), 0, vec![]);
+        fork.add_block(valid.clone()).unwrap();
+        blockchain.add_block(valid.clone()).unwrap();
+
+        // a block spending an output that does not exist, only
+        // noticed when the branch is connected
+        let alice = PrivateKey::new_key();
+        let unknown = OutPoint::new(Hash::hash_bytes(b"unknown"), 0);
+        let bad = spend(&[(unknown, &alice)], vec![output(1, &alice)]);
+        let invalid = mine(&fork, &alice, 0, vec![bad]);
+        assert!(blockchain.add_block(invalid.clone()).is_err());
+        assert_eq!(tip(&blockchain), active.hash());
+        assert_eq!(blockchain.block_height(), 2);
+        assert!(blockchain.side_blocks.contains_key(&valid.hash()));
+        assert!(!blockchain.chain_work.contains_key(&invalid.hash()));
+
+        // neither the block nor anything built on it comes back
+        assert!(matches!(
+            blockchain.add_block(invalid.clone()),
+            Err(BtcError::InvalidBlock)
+        ));
+        fork.blocks.push(invalid);
+        let child = mine(&fork, &alice, 0, vec![]);
+        assert!(matches!(
+            blockchain.add_block(child),
+            Err(BtcError::InvalidBlock)
+        ));
+        assert_eq!(tip(&blockchain), active.hash());
+    }
+
+    #[test]
+    fn switches_to_heavier_chain_from_another_genesis() {
+        let (mut blockchain, genesis, _, _) = funded(&PrivateKey::new_key());
+        let block = mine(&blockchain, &PrivateKey::new_key(), 0, vec![]);
+        blockchain.add_block(block).unwrap();
+
+        let (mut other, _, _, _) = funded(&PrivateKey::new_key());
+        for _ in 0..2 {
+            let block = mine(&other, &PrivateKey::new_key(), 0, vec![]);
+            other.add_block(block).unwrap();
+        }
+        for block in other.blocks() {
+            blockchain.add_block(block.clone()).unwrap();
+        }
+        assert!(blockchain.orphans.is_empty());
+        assert_eq!(tip(&blockchain), tip(&other));
+        assert_eq!(blockchain.block_height(), 3);
+        assert_eq!(blockchain.utxos, other.utxos);
+        assert!(blockchain.side_blocks.contains_key(&genesis.hash()));
+
+        // the old chain is still indexed after a reload
+        let mut saved = vec![];
+        blockchain.save(&mut saved).unwrap();
+        let reloaded = Blockchain::load(saved.as_slice()).unwrap();
+        assert_eq!(reloaded.chain_work, blockchain.chain_work);
+    }
+
+    #[test]
+    fn drops_side_blocks_built_on_an_invalid_block() {
+        let (mut blockchain, mut fork) = fork();
+        for _ in 0..3 {
+            let block = mine(&blockchain, &PrivateKey::new_key(), 0, vec![]);
+            blockchain.add_block(block).unwrap();
+        }
+        let valid = mine(&fork, &PrivateKey::new_key(), 0, vec![]);
+        fork.add_block(valid.clone()).unwrap();
+        blockchain.add_block(valid).unwrap();
+
+        let alice = PrivateKey::new_key();
+        let unknown = OutPoint::new(Hash::hash_bytes(b"unknown"), 0);
+        let bad = spend(&[(unknown, &alice)], vec![output(1, &alice)]);
+        let invalid = mine(&fork, &alice, 0, vec![bad]);
+        blockchain.add_block(invalid.clone()).unwrap();
+        fork.blocks.push(invalid);
+        // two children of the invalid block, neither with enough
+        // work to trigger a reorganization
+        let mut sibling = fork.clone();
+        let child = mine(&fork, &PrivateKey::new_key(), 0, vec![]);
+        blockchain.add_block(child.clone()).unwrap();
+        fork.blocks.push(child);
+        let cousin = mine(&sibling, &PrivateKey::new_key(), 0, vec![]);
+        blockchain.add_block(cousin.clone()).unwrap();
+        sibling.blocks.push(cousin.clone());
+
+        // the branch through `child` has the most work, connecting it fails
+        let last = mine(&fork, &PrivateKey::new_key(), 0, vec![]);
+        assert!(blockchain.add_block(last).is_err());
+        assert_eq!(blockchain.block_height(), 4);
+        assert!(!blockchain.side_blocks.contains_key(&cousin.hash()));
+
+        let on_cousin = mine(&sibling, &PrivateKey::new_key(), 0, vec![]);
+        assert!(matches!(
+            blockchain.add_block(on_cousin),
+            Err(BtcError::InvalidBlock)
+        ));
+        assert_eq!(blockchain.block_height(), 4);
     }
 }
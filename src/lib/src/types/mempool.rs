@@ -0,0 +1,794 @@
+use crate::error::{BtcError, Result};
+use crate::sha256::Hash;
+use crate::types::transaction::{OutPoint, Transaction, TransactionOutput};
+use crate::util::Saveable;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+
+/// Fee, size and number of transactions of a transaction
+/// together with its ancestors or descendants in the mempool
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Package {
+    pub fee: u64,
+    pub size: usize,
+    pub count: usize,
+}
+
+impl Package {
+    fn add(&mut self, entry: &MempoolEntry) {
+        self.fee = self.fee.saturating_add(entry.fee);
+        self.size += entry.size;
+        self.count += 1;
+    }
+
+    fn priority(&self, txid: Hash) -> Priority {
+        Priority {
+            fee: self.fee,
+            size: self.size as u64,
+            txid,
+        }
+    }
+}
+
+/// A transaction waiting to be mined
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MempoolEntry {
+    pub transaction: Transaction,
+    /// when the transaction entered the mempool
+    pub time: DateTime<Utc>,
+    /// value of the inputs minus value of the outputs
+    pub fee: u64,
+    /// length of the transaction's consensus encoding in bytes
+    pub size: usize,
+    // mempool transactions whose outputs this one spends
+    #[serde(skip)]
+    parents: HashSet<Hash>,
+    // mempool transactions spending outputs of this one
+    #[serde(skip)]
+    children: HashSet<Hash>,
+    // this transaction with everything it depends on, what a
+    // miner has to include to get its fee
+    #[serde(skip)]
+    ancestors: Package,
+    // this transaction with everything depending on it, what
+    // is lost by evicting it
+    #[serde(skip)]
+    descendants: Package,
+}
+
+impl MempoolEntry {
+    pub fn new(transaction: Transaction, fee: u64) -> Self {
+        let size = transaction.size();
+        Self {
+            transaction,
+            time: Utc::now(),
+            fee,
+            size,
+            parents: HashSet::new(),
+            children: HashSet::new(),
+            ancestors: Package::default(),
+            descendants: Package::default(),
+        }
+    }
+
+    /// Fee in satoshis per byte
+    pub fn fee_rate(&self) -> f64 {
+        self.fee as f64 / self.size as f64
+    }
+
+    /// This transaction and its unconfirmed ancestors
+    pub fn ancestors(&self) -> Package {
+        self.ancestors
+    }
+
+    /// This transaction and its unconfirmed descendants
+    pub fn descendants(&self) -> Package {
+        self.descendants
+    }
+
+    /// Whether this entry pays more per byte than `other`
+    fn pays_higher_rate_than(&self, other: &MempoolEntry) -> bool {
+        self.fee as u128 * other.size as u128 > other.fee as u128 * self.size as u128
+    }
+}
+
+/// Orders transactions by fee per byte, ties broken by txid
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Priority {
+    fee: u64,
+    size: u64,
+    txid: Hash,
+}
+
+impl Ord for Priority {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // fee / size against other.fee / other.size without dividing
+        (self.fee as u128 * other.size as u128)
+            .cmp(&(other.fee as u128 * self.size as u128))
+            .then_with(|| self.txid.cmp(&other.txid))
+    }
+}
+
+impl PartialOrd for Priority {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Transactions waiting to be mined, indexed by txid, by the
+/// outputs they spend and by fee rate. No two transactions in
+/// the mempool spend the same output. A transaction may spend
+/// outputs of other mempool transactions, its ancestors; it is
+/// ranked together with them, so a child paying a high fee
+/// lifts its parents
+#[derive(Clone, Debug)]
+pub struct Mempool {
+    transactions: HashMap<Hash, MempoolEntry>,
+    // which transaction spends an output
+    spent: HashMap<OutPoint, Hash>,
+    // by fee rate including ancestors, lowest first
+    by_ancestor_fee_rate: BTreeSet<Priority>,
+    // by fee rate including descendants, lowest (evicted) first
+    by_descendant_fee_rate: BTreeSet<Priority>,
+    // encoded size of all transactions together
+    size: usize,
+    max_size: usize,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(crate::MAX_MEMPOOL_SIZE)
+    }
+}
+
+impl Mempool {
+    /// An empty mempool holding at most `max_size` bytes of transactions
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            transactions: HashMap::new(),
+            spent: HashMap::new(),
+            by_ancestor_fee_rate: BTreeSet::new(),
+            by_descendant_fee_rate: BTreeSet::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// Encoded size of all transactions in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn contains(&self, txid: &Hash) -> bool {
+        self.transactions.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &Hash) -> Option<&MempoolEntry> {
+        self.transactions.get(txid)
+    }
+
+    /// An output created by a mempool transaction
+    pub fn output(&self, outpoint: &OutPoint) -> Option<&TransactionOutput> {
+        self.transactions
+            .get(&outpoint.txid)?
+            .transaction
+            .outputs
+            .get(outpoint.index as usize)
+    }
+
+    /// The transaction spending `outpoint`, if any
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<&Hash> {
+        self.spent.get(outpoint)
+    }
+
+    /// Whether a transaction in the mempool spends `outpoint`
+    pub fn is_spent(&self, outpoint: &OutPoint) -> bool {
+        self.spent.contains_key(outpoint)
+    }
+
+    /// Transactions in the mempool spending any of the outputs `transaction` spends
+    pub fn conflicts(&self, transaction: &Transaction) -> Vec<Hash> {
+        let mut conflicts: Vec<Hash> = transaction
+            .inputs
+            .iter()
+            .filter_map(|input| self.spent.get(&input.prev_output))
+            .copied()
+            .collect();
+        conflicts.sort();
+        conflicts.dedup();
+        conflicts
+    }
+
+    /// Mempool transactions `transaction` spends outputs of
+    fn parents_of(&self, transaction: &Transaction) -> HashSet<Hash> {
+        transaction
+            .inputs
+            .iter()
+            .map(|input| input.prev_output.txid)
+            .filter(|txid| self.transactions.contains_key(txid))
+            .collect()
+    }
+
+    /// Everything reachable from `start` following `next`, without `start`
+    fn reachable(
+        &self,
+        start: impl IntoIterator<Item = Hash>,
+        next: fn(&MempoolEntry) -> &HashSet<Hash>,
+    ) -> HashSet<Hash> {
+        let mut found = HashSet::new();
+        let mut stack: Vec<Hash> = start.into_iter().collect();
+        while let Some(txid) = stack.pop() {
+            if let Some(entry) = self.transactions.get(&txid) {
+                for other in next(entry) {
+                    if found.insert(*other) {
+                        stack.push(*other);
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    /// Mempool transactions `txid` depends on, directly or not
+    pub fn ancestors(&self, txid: &Hash) -> HashSet<Hash> {
+        self.reachable([*txid], |entry| &entry.parents)
+    }
+
+    /// Mempool transactions depending on `txid`, directly or not
+    pub fn descendants(&self, txid: &Hash) -> HashSet<Hash> {
+        self.reachable([*txid], |entry| &entry.children)
+    }
+
+    /// Check `transaction` stays within `MAX_MEMPOOL_ANCESTORS` and
+    /// does not take any of its ancestors beyond `MAX_MEMPOOL_DESCENDANTS`,
+    /// both counting the transaction itself
+    pub fn check_limits(&self, transaction: &Transaction) -> Result<()> {
+        let parents = self.parents_of(transaction);
+        let mut ancestors = self.reachable(parents.iter().copied(), |entry| &entry.parents);
+        ancestors.extend(parents);
+        if ancestors.len() + 1 > crate::MAX_MEMPOOL_ANCESTORS {
+            return Err(BtcError::TooManyAncestors);
+        }
+        for ancestor in &ancestors {
+            if self.transactions[ancestor].descendants.count + 1 > crate::MAX_MEMPOOL_DESCENDANTS {
+                return Err(BtcError::TooManyDescendants);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check `entry` may replace the transactions it conflicts with
+    /// and their descendants: it has to pay more than all of them
+    /// together, plus the minimum relay fee for its own size, more per
+    /// byte than each transaction it conflicts with, and it may not
+    /// replace more than `MAX_REPLACEMENTS` or spend outputs of a
+    /// transaction it replaces
+    pub fn check_replacement(&self, entry: &MempoolEntry) -> Result<()> {
+        let conflicts = self.conflicts(&entry.transaction);
+        let mut replaced = self.reachable(conflicts.iter().copied(), |entry| &entry.children);
+        replaced.extend(conflicts.iter().copied());
+        if replaced.len() > crate::MAX_REPLACEMENTS {
+            return Err(BtcError::TooManyReplacements);
+        }
+        if self
+            .parents_of(&entry.transaction)
+            .iter()
+            .any(|parent| replaced.contains(parent))
+        {
+            return Err(BtcError::ReplacementSpendsConflict);
+        }
+        for conflict in &conflicts {
+            if !entry.pays_higher_rate_than(&self.transactions[conflict]) {
+                return Err(BtcError::ReplacementFeeRateTooLow);
+            }
+        }
+        let replaced_fees = replaced.iter().fold(0u64, |fees, txid| {
+            fees.saturating_add(self.transactions[txid].fee)
+        });
+        // the replacement pays for relaying itself on top of
+        // what the replaced transactions paid
+        let required = replaced_fees.saturating_add(crate::MIN_RELAY_FEE_RATE * entry.size as u64);
+        if !conflicts.is_empty() && entry.fee < required {
+            return Err(BtcError::ReplacementFeeTooLow);
+        }
+        Ok(())
+    }
+
+    /// Entries from the highest fee rate, counting their ancestors,
+    /// to the lowest. An entry may come before its ancestors
+    pub fn iter(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.by_ancestor_fee_rate
+            .iter()
+            .rev()
+            .map(|priority| &self.transactions[&priority.txid])
+    }
+
+    /// Recompute the packages of `txid` after its ancestors or
+    /// descendants changed
+    fn refresh(&mut self, txid: &Hash) {
+        let mut ancestors = Package::default();
+        for ancestor in self.ancestors(txid) {
+            ancestors.add(&self.transactions[&ancestor]);
+        }
+        let mut descendants = Package::default();
+        for descendant in self.descendants(txid) {
+            descendants.add(&self.transactions[&descendant]);
+        }
+        let Some(entry) = self.transactions.get_mut(txid) else {
+            return;
+        };
+        self.by_ancestor_fee_rate
+            .remove(&entry.ancestors.priority(*txid));
+        self.by_descendant_fee_rate
+            .remove(&entry.descendants.priority(*txid));
+        ancestors.add(entry);
+        descendants.add(entry);
+        entry.ancestors = ancestors;
+        entry.descendants = descendants;
+        self.by_ancestor_fee_rate.insert(ancestors.priority(*txid));
+        self.by_descendant_fee_rate
+            .insert(descendants.priority(*txid));
+    }
+
+    /// Add a transaction, replacing the ones spending the same outputs
+    /// and their descendants whether or not `check_replacement` allows
+    /// it. While the mempool is over its size limit the transactions
+    /// with the lowest fee rate counting their descendants are evicted
+    /// with those descendants. If that includes the new one the
+    /// mempool is left as it was and only the new one is returned,
+    /// otherwise every entry that was removed
+    pub fn insert(&mut self, mut entry: MempoolEntry) -> Vec<MempoolEntry> {
+        let txid = entry.transaction.hash();
+        let mut removed = vec![];
+        for conflict in self.conflicts(&entry.transaction) {
+            removed.extend(self.remove_with_descendants(&conflict));
+        }
+        // a transaction already in the mempool is replaced
+        removed.extend(self.remove_with_descendants(&txid));
+
+        entry.parents = self.parents_of(&entry.transaction);
+        entry.children.clear();
+        entry.ancestors = Package::default();
+        entry.descendants = Package::default();
+        for parent in &entry.parents {
+            if let Some(parent) = self.transactions.get_mut(parent) {
+                parent.children.insert(txid);
+            }
+        }
+        for input in &entry.transaction.inputs {
+            self.spent.insert(input.prev_output, txid);
+        }
+        self.size += entry.size;
+        self.transactions.insert(txid, entry);
+        self.refresh(&txid);
+        for ancestor in self.ancestors(&txid) {
+            self.refresh(&ancestor);
+        }
+
+        while self.size > self.max_size {
+            let Some(lowest) = self.by_descendant_fee_rate.first().copied() else {
+                break;
+            };
+            removed.extend(self.remove_with_descendants(&lowest.txid));
+        }
+        if !self.transactions.contains_key(&txid) {
+            let (rejected, replaced) = removed
+                .into_iter()
+                .partition(|entry| entry.transaction.hash() == txid);
+            self.restore(replaced);
+            return rejected;
+        }
+        removed
+    }
+
+    /// Put back entries an insert removed, parents before children
+    fn restore(&mut self, mut pending: Vec<MempoolEntry>) {
+        while !pending.is_empty() {
+            let waiting: HashSet<Hash> = pending
+                .iter()
+                .map(|entry| entry.transaction.hash())
+                .collect();
+            let (ready, rest): (Vec<MempoolEntry>, Vec<MempoolEntry>) =
+                pending.into_iter().partition(|entry| {
+                    !entry
+                        .transaction
+                        .inputs
+                        .iter()
+                        .any(|input| waiting.contains(&input.prev_output.txid))
+                });
+            for entry in ready {
+                self.insert(entry);
+            }
+            pending = rest;
+        }
+    }
+
+    /// Remove a single transaction, its descendants stay. Used
+    /// for transactions that made it into a block
+    pub fn remove(&mut self, txid: &Hash) -> Option<MempoolEntry> {
+        let mut related = self.ancestors(txid);
+        related.extend(self.descendants(txid));
+        let entry = self.transactions.remove(txid)?;
+        for input in &entry.transaction.inputs {
+            self.spent.remove(&input.prev_output);
+        }
+        self.by_ancestor_fee_rate
+            .remove(&entry.ancestors.priority(*txid));
+        self.by_descendant_fee_rate
+            .remove(&entry.descendants.priority(*txid));
+        self.size -= entry.size;
+        for parent in &entry.parents {
+            if let Some(parent) = self.transactions.get_mut(parent) {
+                parent.children.remove(txid);
+            }
+        }
+        for child in &entry.children {
+            if let Some(child) = self.transactions.get_mut(child) {
+                child.parents.remove(txid);
+            }
+        }
+        for other in &related {
+            self.refresh(other);
+        }
+        Some(entry)
+    }
+
+    /// Remove a transaction and everything spending its outputs,
+    /// returns the removed entries
+    pub fn remove_with_descendants(&mut self, txid: &Hash) -> Vec<MempoolEntry> {
+        let mut removed = vec![];
+        if !self.transactions.contains_key(txid) {
+            return removed;
+        }
+        let descendants = self.descendants(txid);
+        removed.extend(self.remove(txid));
+        for descendant in descendants {
+            removed.extend(self.remove(&descendant));
+        }
+        removed
+    }
+
+    /// All entries, parents before their children
+    pub fn into_entries(self) -> Vec<MempoolEntry> {
+        let mut entries: Vec<MempoolEntry> = self.transactions.into_values().collect();
+        // a parent has fewer ancestors than any of its children
+        entries.sort_by_key(|entry| entry.ancestors.count);
+        entries
+    }
+
+    /// Keep only the entries `keep` returns true for, dropping the
+    /// descendants of the others too. Returns the removed ones
+    pub fn retain<F: FnMut(&MempoolEntry) -> bool>(&mut self, mut keep: F) -> Vec<MempoolEntry> {
+        let dropped: Vec<Hash> = self
+            .transactions
+            .iter()
+            .filter(|(_, entry)| !keep(entry))
+            .map(|(txid, _)| *txid)
+            .collect();
+        dropped
+            .iter()
+            .flat_map(|txid| self.remove_with_descendants(txid))
+            .collect()
+    }
+}
+
+/// The entries are stored parents first, so loading them
+/// rebuilds the same mempool
+impl Saveable for Mempool {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        let entries: Vec<MempoolEntry> = ciborium::de::from_reader(reader)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize mempool"))?;
+        let mut mempool = Mempool::default();
+        for entry in entries {
+            mempool.insert(entry);
+        }
+        Ok(mempool)
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        let mut entries: Vec<&MempoolEntry> = self.transactions.values().collect();
+        entries.sort_by_key(|entry| entry.ancestors.count);
+        ciborium::ser::into_writer(&entries, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize mempool"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::types::{SigHash, TransactionInput, TransactionOutput};
+
+    /// A transaction spending `prev_outputs`, the signature does not
+    /// matter to the mempool
+    fn transaction(key: &PrivateKey, prev_outputs: &[OutPoint], value: u64) -> Transaction {
+        let inputs = prev_outputs
+            .iter()
+            .map(|prev_output| TransactionInput {
+                prev_output: *prev_output,
+                signature: Signature::sign(&Hash::zero(), key),
+                sighash: SigHash::ALL,
+            })
+            .collect();
+        Transaction::new(
+            inputs,
+            vec![TransactionOutput {
+                value,
+                pubkey: key.public_key(),
+            }],
+        )
+    }
+
+    fn outpoint(index: u32) -> OutPoint {
+        OutPoint::new(Hash::zero(), index)
+    }
+
+    #[test]
+    fn iterates_by_fee_rate() {
+        let key = PrivateKey::new_key();
+        let mut mempool = Mempool::default();
+        mempool.insert(MempoolEntry::new(transaction(&key, &[outpoint(0)], 1), 10));
+        mempool.insert(MempoolEntry::new(transaction(&key, &[outpoint(1)], 2), 30));
+        mempool.insert(MempoolEntry::new(
+            transaction(&key, &[outpoint(2), outpoint(3)], 3),
+            20,
+        ));
+        let fees: Vec<u64> = mempool.iter().map(|entry| entry.fee).collect();
+        assert_eq!(fees, vec![30, 20, 10]);
+    }
+
+    #[test]
+    fn replaces_conflicting_transactions() {
+        let key = PrivateKey::new_key();
+        let mut mempool = Mempool::default();
+        let first = transaction(&key, &[outpoint(0), outpoint(1)], 1);
+        let second = transaction(&key, &[outpoint(1), outpoint(2)], 2);
+        mempool.insert(MempoolEntry::new(first.clone(), 10));
+        let removed = mempool.insert(MempoolEntry::new(second.clone(), 20));
+
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].transaction.hash(), first.hash());
+        assert_eq!(mempool.len(), 1);
+        // outputs only the replaced transaction spent are free again
+        assert!(!mempool.is_spent(&outpoint(0)));
+        assert_eq!(mempool.spender(&outpoint(2)), Some(&second.hash()));
+        assert_eq!(mempool.size(), second.size());
+    }
+
+    #[test]
+    fn replacement_pays_more_in_total_and_per_byte() {
+        let key = PrivateKey::new_key();
+        let mut mempool = Mempool::default();
+        let original = transaction(&key, &[outpoint(0)], 1);
+        mempool.insert(MempoolEntry::new(original.clone(), 1_000));
+
+        // same size, the fee has to cover the replaced one and its own relay
+        let replacement = transaction(&key, &[outpoint(0)], 2);
+        let relay = crate::MIN_RELAY_FEE_RATE * replacement.size() as u64;
+        let entry = MempoolEntry::new(replacement.clone(), 1_000 + relay - 1);
+        assert!(matches!(
+            mempool.check_replacement(&entry),
+            Err(BtcError::ReplacementFeeTooLow)
+        ));
+        let entry = MempoolEntry::new(replacement, 1_000 + relay);
+        mempool.check_replacement(&entry).unwrap();
+
+        // more in total than both originals, but less per byte than the small one
+        let small = transaction(&key, &[outpoint(1)], 3);
+        mempool.insert(MempoolEntry::new(small.clone(), 10_000));
+        let big = transaction(&key, &[outpoint(0), outpoint(1), outpoint(2)], 4);
+        let entry = MempoolEntry::new(big, 12_000);
+        assert!(!entry.pays_higher_rate_than(&mempool.transactions[&small.hash()]));
+        assert!(matches!(
+            mempool.check_replacement(&entry),
+            Err(BtcError::ReplacementFeeRateTooLow)
+        ));
+
+        // no conflict, nothing to pay for
+        let unrelated = transaction(&key, &[outpoint(3)], 5);
+        mempool
+            .check_replacement(&MempoolEntry::new(unrelated, 0))
+            .unwrap();
+    }
+
+    #[test]
+    fn replacement_evicts_limited_transactions() {
+        let key = PrivateKey::new_key();
+        let mut mempool = Mempool::default();
+        let count = crate::MAX_REPLACEMENTS as u32 + 1;
+        for index in 0..count {
+            mempool.insert(MempoolEntry::new(
+                transaction(&key, &[outpoint(index)], index as u64),
+                1_000,
+            ));
+        }
+        let outpoints: Vec<OutPoint> = (0..count).map(outpoint).collect();
+        let entry = MempoolEntry::new(transaction(&key, &outpoints, 0), u64::MAX / 2);
+        assert!(matches!(
+            mempool.check_replacement(&entry),
+            Err(BtcError::TooManyReplacements)
+        ));
+        let entry = MempoolEntry::new(
+            transaction(&key, &outpoints[..count as usize - 1], 0),
+            u64::MAX / 2,
+        );
+        mempool.check_replacement(&entry).unwrap();
+    }
+
+    #[test]
+    fn replacement_may_not_spend_what_it_replaces() {
+        let key = PrivateKey::new_key();
+        let mut mempool = Mempool::default();
+        let parent = transaction(&key, &[outpoint(0)], 1);
+        mempool.insert(MempoolEntry::new(parent.clone(), 1_000));
+        let child = transaction(&key, &[OutPoint::new(parent.hash(), 0)], 2);
+        mempool.insert(MempoolEntry::new(child.clone(), 1_000));
+
+        // conflicts with the child and spends the parent, fine
+        let sibling = transaction(&key, &[OutPoint::new(parent.hash(), 0)], 3);
+        mempool
+            .check_replacement(&MempoolEntry::new(sibling, 100_000))
+            .unwrap();
+        // conflicts with the parent and spends the child it would evict
+        let entry = MempoolEntry::new(
+            transaction(&key, &[outpoint(0), OutPoint::new(child.hash(), 0)], 4),
+            100_000,
+        );
+        assert!(matches!(
+            mempool.check_replacement(&entry),
+            Err(BtcError::ReplacementSpendsConflict)
+        ));
+    }
+
+    #[test]
+    fn evicts_lowest_fee_rate_when_full() {
+        let key = PrivateKey::new_key();
+        let low = transaction(&key, &[outpoint(0)], 1);
+        let high = transaction(&key, &[outpoint(1)], 2);
+        let lowest = transaction(&key, &[outpoint(2)], 3);
+        // room for two transactions of the same size
+        let mut mempool = Mempool::new(low.size() * 2);
+        mempool.insert(MempoolEntry::new(low.clone(), 10));
+        mempool.insert(MempoolEntry::new(high.clone(), 30));
+
+        // a new transaction paying less than everything is turned away
+        let removed = mempool.insert(MempoolEntry::new(lowest.clone(), 5));
+        assert_eq!(removed[0].transaction.hash(), lowest.hash());
+
+        let removed = mempool.insert(MempoolEntry::new(transaction(&key, &[outpoint(3)], 4), 20));
+        assert_eq!(removed[0].transaction.hash(), low.hash());
+        assert!(mempool.contains(&high.hash()));
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn evicted_replacement_leaves_conflicts_in_place() {
+        let key = PrivateKey::new_key();
+        let parent = transaction(&key, &[outpoint(0)], 1);
+        let child = transaction(&key, &[OutPoint::new(parent.hash(), 0)], 2);
+        let other = transaction(&key, &[outpoint(1)], 3);
+        let mut mempool = Mempool::new(parent.size() + child.size() + other.size());
+        mempool.insert(MempoolEntry::new(parent.clone(), 10));
+        mempool.insert(MempoolEntry::new(child.clone(), 10));
+        mempool.insert(MempoolEntry::new(other.clone(), 1_000));
+
+        // pays more than what it replaces but is too big for its fee
+        let outpoints: Vec<OutPoint> = [0].into_iter().chain(10..20).map(outpoint).collect();
+        let replacement = transaction(&key, &outpoints, 4);
+        let removed = mempool.insert(MempoolEntry::new(replacement.clone(), 30));
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].transaction.hash(), replacement.hash());
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.spender(&outpoint(0)), Some(&parent.hash()));
+        assert_eq!(mempool.get(&child.hash()).unwrap().ancestors().count, 2);
+        assert!(mempool.contains(&other.hash()));
+    }
+
+    #[test]
+    fn child_pays_for_parent() {
+        let key = PrivateKey::new_key();
+        let mut mempool = Mempool::default();
+        let parent = transaction(&key, &[outpoint(0)], 1);
+        let other = transaction(&key, &[outpoint(1)], 2);
+        let child = transaction(&key, &[OutPoint::new(parent.hash(), 0)], 3);
+        mempool.insert(MempoolEntry::new(parent.clone(), 100));
+        mempool.insert(MempoolEntry::new(other.clone(), 500));
+        mempool.insert(MempoolEntry::new(child.clone(), 2_000));
+
+        let parent_entry = mempool.get(&parent.hash()).unwrap();
+        assert_eq!(parent_entry.descendants().fee, 2_100);
+        assert_eq!(parent_entry.descendants().count, 2);
+        assert_eq!(mempool.get(&child.hash()).unwrap().ancestors().fee, 2_100);
+        assert_eq!(
+            mempool.ancestors(&child.hash()),
+            HashSet::from([parent.hash()])
+        );
+        // the child together with its parent beats the other transaction
+        let order: Vec<Hash> = mempool
+            .iter()
+            .map(|entry| entry.transaction.hash())
+            .collect();
+        assert_eq!(order, vec![child.hash(), other.hash(), parent.hash()]);
+
+        // when the parent is mined the child stays on its own
+        mempool.remove(&parent.hash());
+        let child_entry = mempool.get(&child.hash()).unwrap();
+        assert_eq!(child_entry.ancestors().count, 1);
+        assert_eq!(child_entry.ancestors().fee, 2_000);
+    }
+
+    #[test]
+    fn removing_a_parent_removes_descendants() {
+        let key = PrivateKey::new_key();
+        let mut mempool = Mempool::default();
+        let parent = transaction(&key, &[outpoint(0)], 1);
+        let child = transaction(&key, &[OutPoint::new(parent.hash(), 0)], 2);
+        let grandchild = transaction(&key, &[OutPoint::new(child.hash(), 0)], 3);
+        for transaction in [&parent, &child, &grandchild] {
+            mempool.insert(MempoolEntry::new(transaction.clone(), 1_000));
+        }
+
+        // a conflict with the parent replaces the whole chain
+        let replacement = transaction(&key, &[outpoint(0)], 4);
+        let removed = mempool.insert(MempoolEntry::new(replacement.clone(), 5_000));
+        assert_eq!(removed.len(), 3);
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.size(), replacement.size());
+        assert!(!mempool.is_spent(&OutPoint::new(parent.hash(), 0)));
+    }
+
+    #[test]
+    fn limits_unconfirmed_chains() {
+        let key = PrivateKey::new_key();
+        let mut mempool = Mempool::default();
+        let mut prev_output = outpoint(0);
+        for value in 0..crate::MAX_MEMPOOL_ANCESTORS as u64 {
+            let next = transaction(&key, &[prev_output], value);
+            mempool.check_limits(&next).unwrap();
+            prev_output = OutPoint::new(next.hash(), 0);
+            mempool.insert(MempoolEntry::new(next, 1_000));
+        }
+        let too_deep = transaction(&key, &[prev_output], 0);
+        assert!(matches!(
+            mempool.check_limits(&too_deep),
+            Err(BtcError::TooManyAncestors)
+        ));
+    }
+
+    #[test]
+    fn limits_descendants() {
+        let key = PrivateKey::new_key();
+        let mut mempool = Mempool::default();
+        // a parent with many outputs, each spent by its own child
+        let outputs = (0..crate::MAX_MEMPOOL_DESCENDANTS as u64)
+            .map(|value| TransactionOutput {
+                value,
+                pubkey: key.public_key(),
+            })
+            .collect();
+        let parent = Transaction::new(transaction(&key, &[outpoint(0)], 0).inputs, outputs);
+        mempool.insert(MempoolEntry::new(parent.clone(), 1_000));
+        for index in 0..crate::MAX_MEMPOOL_DESCENDANTS as u32 - 1 {
+            let child = transaction(&key, &[OutPoint::new(parent.hash(), index)], 0);
+            mempool.check_limits(&child).unwrap();
+            mempool.insert(MempoolEntry::new(child, 1_000));
+        }
+        let last = crate::MAX_MEMPOOL_DESCENDANTS as u32 - 1;
+        let child = transaction(&key, &[OutPoint::new(parent.hash(), last)], 0);
+        assert!(matches!(
+            mempool.check_limits(&child),
+            Err(BtcError::TooManyDescendants)
+        ));
+    }
+}
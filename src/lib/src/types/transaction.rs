@@ -1,44 +1,210 @@
 use crate::crypto::{PublicKey, Signature};
+use crate::encoding::{Encode, ENCODING_VERSION};
+use crate::error::{BtcError, Result};
 use crate::sha256::Hash;
 use crate::util::Saveable;
 use serde::{Deserialize, Serialize};
-use std::io::{Error as IoError, ErrorKind as IoErrorKind};
-use uuid::Uuid;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult, Write};
+use std::ops::BitOr;
+
+/// Selects the parts of a transaction an input's signature commits to.
+/// The signed input itself is always covered.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SigHash(pub(crate) u8);
+
+impl SigHash {
+    /// sign all inputs and all outputs
+    pub const ALL: SigHash = SigHash(0x01);
+    /// sign all inputs and no outputs, anyone may choose where the coins go
+    pub const NONE: SigHash = SigHash(0x02);
+    /// sign all inputs and only the output with the same index as the input
+    pub const SINGLE: SigHash = SigHash(0x03);
+    /// combined with one of the above: sign only this input,
+    /// so others can add their own inputs
+    pub const ANYONECANPAY: SigHash = SigHash(0x80);
+
+    fn base(self) -> u8 {
+        self.0 & !Self::ANYONECANPAY.0
+    }
+
+    pub fn anyone_can_pay(self) -> bool {
+        self.0 & Self::ANYONECANPAY.0 != 0
+    }
+}
+
+impl BitOr for SigHash {
+    type Output = SigHash;
+
+    fn bitor(self, rhs: SigHash) -> SigHash {
+        SigHash(self.0 | rhs.0)
+    }
+}
+
+/// What gets signed for an input: the transaction without any
+/// signatures, reduced as the input's SigHash asks for
+struct SigHashPreimage<'a> {
+    sighash: SigHash,
+    input: &'a OutPoint,
+    inputs: &'a [OutPoint],
+    outputs: &'a [TransactionOutput],
+}
+
+impl Encode for SigHashPreimage<'_> {
+    fn encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        ENCODING_VERSION.encode(writer)?;
+        self.sighash.encode(writer)?;
+        self.input.encode(writer)?;
+        self.inputs.encode(writer)?;
+        self.outputs.encode(writer)
+    }
+}
+
+/// Reference to an output: the hash of the transaction
+/// that created it and its index in that transaction's outputs
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub txid: Hash,
+    pub index: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: Hash, index: u32) -> Self {
+        Self { txid, index }
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionInput {
-    /// hash of the transaction output which is linked into this
-    /// transaction as input
-    pub pre_transaction_output_hash: Hash,
+    /// the output of a previous transaction which is linked
+    /// into this transaction as input
+    pub prev_output: OutPoint,
     /// this is how the user proves the output of the previous transaction
     pub signature: Signature,
+    /// parts of the transaction the signature commits to
+    pub sighash: SigHash,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct TransactionOutput {
     pub value: u64,
-    pub unique_id: Uuid,
     pub pubkey: PublicKey,
 }
 
+impl TransactionInput {
+    /// Length of the consensus encoding of an input
+    pub const SIZE: usize = 101;
+}
+
 impl TransactionOutput {
-    pub fn hash(&self) -> Hash {
-        Hash::hash(self)
-    }
+    /// Length of the consensus encoding of an output
+    pub const SIZE: usize = 41;
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Transaction {
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
+    /// height of the block a coinbase transaction belongs to, keeps
+    /// coinbase transactions paying the same outputs apart.
+    /// None for every other transaction
+    pub coinbase_height: Option<u64>,
 }
 impl Transaction {
     pub fn new(inputs: Vec<TransactionInput>, outputs: Vec<TransactionOutput>) -> Self {
-        Self { inputs, outputs }
+        Self {
+            inputs,
+            outputs,
+            coinbase_height: None,
+        }
+    }
+
+    pub fn coinbase(height: u64, outputs: Vec<TransactionOutput>) -> Self {
+        Self {
+            inputs: vec![],
+            outputs,
+            coinbase_height: Some(height),
+        }
+    }
+
+    /// Length of the consensus encoding of a non-coinbase transaction
+    /// with `inputs` inputs and `outputs` outputs, known before signing
+    pub fn size_of(inputs: usize, outputs: usize) -> usize {
+        // version, coinbase_height flag and the two list lengths
+        4 + 1 + 4 + inputs * TransactionInput::SIZE + 4 + outputs * TransactionOutput::SIZE
+    }
+
+    /// Length of the consensus encoding in bytes, the size fee rates refer to
+    pub fn size(&self) -> usize {
+        let height = if self.coinbase_height.is_some() { 8 } else { 0 };
+        Self::size_of(self.inputs.len(), self.outputs.len()) + height
     }
 
+    /// The transaction id, hash of the consensus encoding
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        Hash::hash_bytes(&self.encoded())
+    }
+
+    /// Outpoints of this transaction's outputs
+    pub fn outpoints(&self) -> impl Iterator<Item = (OutPoint, &TransactionOutput)> {
+        let txid = self.hash();
+        self.outputs
+            .iter()
+            .enumerate()
+            .map(move |(index, output)| (OutPoint::new(txid, index as u32), output))
+    }
+
+    /// Hash the signature of input `index` has to sign.
+    /// `inputs` are the outpoints spent by all the inputs, so the hash
+    /// can be computed before any input is signed
+    pub fn signature_hash(
+        inputs: &[OutPoint],
+        outputs: &[TransactionOutput],
+        index: usize,
+        sighash: SigHash,
+    ) -> Result<Hash> {
+        let input = inputs.get(index).ok_or(BtcError::InvalidTransactionInput)?;
+        let inputs = if sighash.anyone_can_pay() {
+            &[]
+        } else {
+            inputs
+        };
+        let outputs = match sighash.base() {
+            0x01 => outputs,
+            0x02 => &[],
+            0x03 => {
+                // there has to be an output to commit to
+                let output = outputs.get(index).ok_or(BtcError::InvalidSignature)?;
+                std::slice::from_ref(output)
+            }
+            _ => return Err(BtcError::InvalidSignature),
+        };
+        let preimage = SigHashPreimage {
+            sighash,
+            input,
+            inputs,
+            outputs,
+        };
+        Ok(Hash::hash_bytes(&preimage.encoded()))
+    }
+
+    /// Hash the signature of input `index` commits to
+    pub fn sighash(&self, index: usize) -> Result<Hash> {
+        let input = self
+            .inputs
+            .get(index)
+            .ok_or(BtcError::InvalidTransactionInput)?;
+        let inputs: Vec<OutPoint> = self.inputs.iter().map(|input| input.prev_output).collect();
+        Self::signature_hash(&inputs, &self.outputs, index, input.sighash)
+    }
+
+    /// Check the signature of input `index` against the output it spends
+    pub fn verify_input(&self, index: usize, prev_output: &TransactionOutput) -> bool {
+        match self.sighash(index) {
+            Ok(hash) => self.inputs[index]
+                .signature
+                .verify(&hash, &prev_output.pubkey),
+            Err(_) => false,
+        }
     }
 }
 impl Saveable for Transaction {
@@ -56,3 +222,125 @@ impl Saveable for Transaction {
             .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize Transaction"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+    fn output(value: u64, key: &PrivateKey) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            pubkey: key.public_key(),
+        }
+    }
+
+    /// Outputs of a coinbase transaction, ready to be spent
+    fn coins(key: &PrivateKey, count: usize) -> Vec<(OutPoint, TransactionOutput)> {
+        let coinbase = Transaction::coinbase(0, vec![output(50, key); count]);
+        coinbase
+            .outpoints()
+            .map(|(outpoint, output)| (outpoint, output.clone()))
+            .collect()
+    }
+
+    /// Spend `prev_outputs` to two outputs, signing each input with `sighash`
+    fn signed(
+        key: &PrivateKey,
+        prev_outputs: &[(OutPoint, TransactionOutput)],
+        sighash: SigHash,
+    ) -> Transaction {
+        let outputs = vec![output(10, key), output(20, key)];
+        let outpoints: Vec<OutPoint> = prev_outputs.iter().map(|(outpoint, _)| *outpoint).collect();
+        let inputs = (0..outpoints.len())
+            .map(|index| TransactionInput {
+                prev_output: outpoints[index],
+                signature: Signature::sign(
+                    &Transaction::signature_hash(&outpoints, &outputs, index, sighash).unwrap(),
+                    key,
+                ),
+                sighash,
+            })
+            .collect();
+        Transaction::new(inputs, outputs)
+    }
+
+    #[test]
+    fn all_commits_to_every_output() {
+        let key = PrivateKey::new_key();
+        let prev = coins(&key, 2);
+        let mut transaction = signed(&key, &prev, SigHash::ALL);
+        assert!(transaction.verify_input(0, &prev[0].1));
+        assert!(transaction.verify_input(1, &prev[1].1));
+
+        transaction.outputs[1].value = 30;
+        assert!(!transaction.verify_input(0, &prev[0].1));
+        assert!(!transaction.verify_input(1, &prev[1].1));
+    }
+
+    #[test]
+    fn signature_can_not_be_moved_to_other_input() {
+        let key = PrivateKey::new_key();
+        let prev = coins(&key, 2);
+        let mut transaction = signed(&key, &prev, SigHash::ALL);
+        transaction.inputs.swap(0, 1);
+        transaction.inputs[0].prev_output = prev[0].0;
+        transaction.inputs[1].prev_output = prev[1].0;
+        assert!(!transaction.verify_input(0, &prev[0].1));
+    }
+
+    #[test]
+    fn none_leaves_outputs_open() {
+        let key = PrivateKey::new_key();
+        let prev = coins(&key, 1);
+        let mut transaction = signed(&key, &prev, SigHash::NONE);
+        transaction.outputs.pop();
+        transaction.outputs[0].value = 40;
+        assert!(transaction.verify_input(0, &prev[0].1));
+    }
+
+    #[test]
+    fn single_commits_to_matching_output() {
+        let key = PrivateKey::new_key();
+        let prev = coins(&key, 1);
+        let mut transaction = signed(&key, &prev, SigHash::SINGLE);
+        transaction.outputs[1].value = 40;
+        assert!(transaction.verify_input(0, &prev[0].1));
+        transaction.outputs[0].value = 40;
+        assert!(!transaction.verify_input(0, &prev[0].1));
+    }
+
+    #[test]
+    fn single_without_matching_output_is_invalid() {
+        let key = PrivateKey::new_key();
+        let prev = coins(&key, 3);
+        let outpoints: Vec<OutPoint> = prev.iter().map(|(outpoint, _)| *outpoint).collect();
+        let result =
+            Transaction::signature_hash(&outpoints, &[output(10, &key)], 2, SigHash::SINGLE);
+        assert!(matches!(result, Err(BtcError::InvalidSignature)));
+    }
+
+    #[test]
+    fn anyone_can_pay_allows_more_inputs() {
+        let key = PrivateKey::new_key();
+        let prev = coins(&key, 1);
+        let other = signed(&key, &coins(&key, 2)[1..], SigHash::ALL);
+
+        let mut transaction = signed(&key, &prev, SigHash::ALL | SigHash::ANYONECANPAY);
+        transaction.inputs.push(other.inputs[0].clone());
+        assert!(transaction.verify_input(0, &prev[0].1));
+
+        let mut transaction = signed(&key, &prev, SigHash::ALL);
+        transaction.inputs.push(other.inputs[0].clone());
+        assert!(!transaction.verify_input(0, &prev[0].1));
+    }
+
+    #[test]
+    fn unknown_sighash_is_invalid() {
+        let key = PrivateKey::new_key();
+        let prev = coins(&key, 1);
+        let mut transaction = signed(&key, &prev, SigHash::ALL);
+        transaction.inputs[0].sighash = SigHash(0x04);
+        assert!(!transaction.verify_input(0, &prev[0].1));
+    }
+}
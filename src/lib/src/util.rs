@@ -1,3 +1,4 @@
+use crate::encoding::Encode;
 use crate::sha256::Hash;
 use crate::types::Transaction;
 use serde;
@@ -6,20 +7,22 @@ use std::io::{Read, Result as IoResult, Write};
 use std::path::Path;
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
-pub struct MerkleRoot(Hash);
+pub struct MerkleRoot(pub(crate) Hash);
 
 impl MerkleRoot {
     pub fn calculate(transactions: &[Transaction]) -> Self {
         let mut layer: Vec<Hash> = vec![];
         for tx in transactions {
-            layer.push(Hash::hash(tx))
+            layer.push(tx.hash())
         }
         while layer.len() > 1 {
             let mut new_layer = vec![];
             for pair in layer.chunks(2) {
                 let left: Hash = pair[0];
                 let right: &Hash = pair.get(1).unwrap_or(&pair[0]);
-                new_layer.push(Hash::hash(&[left, *right]))
+                let mut bytes = left.encoded();
+                bytes.extend(right.encoded());
+                new_layer.push(Hash::hash_bytes(&bytes))
             }
             layer = new_layer;
         }
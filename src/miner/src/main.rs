@@ -1,19 +1,65 @@
 use anyhow::{anyhow, Result};
 use btclib::crypto::PublicKey;
-use btclib::network::Message;
+use btclib::mining::{Cancel, Miner};
+use btclib::network::{Message, MessageCodec, Version};
+use btclib::types::Block;
 use btclib::util::Saveable;
+use futures::{SinkExt, StreamExt};
 use std::env;
 use std::process::exit;
+use std::time::Duration;
 use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+// how often the node is asked whether the template is still current
+const TEMPLATE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+type Connection = Framed<TcpStream, MessageCodec>;
 
 fn usage() -> ! {
     eprintln!(
-        "Usage: {} <address> <public_key_file>",
+        "Usage: {} <address> <public_key_file> [threads]",
         env::args().next().unwrap()
     );
     exit(1);
 }
 
+/// Send `message` and wait for the answer,
+/// answering the node's pings meanwhile
+async fn request(connection: &mut Connection, message: Message) -> Result<Message> {
+    connection.send(message).await?;
+    loop {
+        match connection
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("node closed the connection"))??
+        {
+            Message::Ping(nonce) => connection.send(Message::Pong(nonce)).await?,
+            response => return Ok(response),
+        }
+    }
+}
+
+async fn fetch_template(connection: &mut Connection, public_key: &PublicKey) -> Result<Block> {
+    match request(connection, Message::FetchTemplate(public_key.clone())).await? {
+        Message::Template(template) => {
+            println!(
+                "received template with {} transactions",
+                template.transactions.len()
+            );
+            Ok(template)
+        }
+        _ => Err(anyhow!("Unexpected response to FetchTemplate")),
+    }
+}
+
+async fn validate_template(connection: &mut Connection, template: &Block) -> Result<bool> {
+    match request(connection, Message::ValidateTemplate(template.clone())).await? {
+        Message::TemplateValidity(valid) => Ok(valid),
+        _ => Err(anyhow!("Unexpected response to ValidateTemplate")),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let address = match env::args().nth(1) {
@@ -24,32 +70,62 @@ async fn main() -> Result<()> {
         Some(public_key_file) => public_key_file,
         None => usage(),
     };
+    let miner = match env::args().nth(3).map(|threads| threads.parse()) {
+        None => Miner::with_available_parallelism(),
+        Some(Ok(threads @ 1..)) => Miner::new(threads),
+        Some(_) => usage(),
+    };
     let public_key = PublicKey::load_from_file(&public_key_file)
         .map_err(|e| anyhow!("Error reading publickey: {}", e))?;
-    todo!()
+
+    let mut stream = TcpStream::connect(&address).await?;
+    let user_agent = format!("btclib-miner/{}", env!("CARGO_PKG_VERSION"));
+    Version::new(0, user_agent, false)
+        .handshake(&mut stream)
+        .await?;
+    let mut connection = Framed::new(stream, MessageCodec::default());
+    println!(
+        "connected to {}, mining on {} threads",
+        address,
+        miner.threads()
+    );
+
+    let mut template = fetch_template(&mut connection, &public_key).await?;
+    loop {
+        // mining is CPU bound, keep it off the async runtime
+        let cancel = Cancel::new();
+        let mut job = tokio::task::spawn_blocking({
+            let miner = miner.clone();
+            let header = template.header.clone();
+            let cancel = cancel.clone();
+            move || {
+                miner.mine(header, &cancel, |hashrate| {
+                    println!("hashrate: {:.0} H/s", hashrate)
+                })
+            }
+        });
+
+        let mut check = tokio::time::interval(TEMPLATE_CHECK_INTERVAL);
+        // the first tick completes right away
+        check.tick().await;
+        let mined = loop {
+            tokio::select! {
+                mined = &mut job => break mined?,
+                _ = check.tick() => {
+                    if !validate_template(&mut connection, &template).await? {
+                        println!("template is stale, fetching a new one");
+                        cancel.cancel();
+                        break job.await?;
+                    }
+                }
+            }
+        };
+
+        if let Some(header) = mined.header {
+            template.header = header;
+            println!("block mined: {}", template.header.hash());
+            connection.send(Message::SubmitTemplate(template)).await?;
+        }
+        template = fetch_template(&mut connection, &public_key).await?;
+    }
 }
-/*
-   let (path, steps) = if let (Some(_1), Some(_2)) = (env::args().nth(1), env::args().nth(2)) {
-       (_1, _2)
-   } else {
-       eprintln!("Usage: miner <block-file> <steps>");
-       exit(1);
-   };
-   let steps: usize = if let Ok(s @ 1..usize::MAX) = steps.parse() {
-       s
-   } else {
-       eprintln!("<steps> should be a positive integer");
-       exit(1);
-   };
-
-   let og_block = Block::load_from_file(path).expect("failed to load block");
-   let mut block = og_block.clone();
-   while !block.header.mine(steps) {
-       println!("mining")
-   }
-   println!("original: {:#?}", og_block);
-   println!("hash: {}", og_block.header.hash());
-   // print mined block and its hash
-   println!("final: {:#?}", block);
-   println!("hash: {}", block.header.hash());
-*/
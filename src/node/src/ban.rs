@@ -0,0 +1,325 @@
+use anyhow::{anyhow, Result};
+use btclib::error::{BtcError, NetworkError};
+use btclib::network::{Message, MessageCodec};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fs::File;
+use std::net::IpAddr;
+use std::path::Path;
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+use crate::BANS;
+use crate::{handler, sync};
+
+/// misbehavior points after which a peer is banned
+const BAN_THRESHOLD: u32 = 100;
+/// how long a ban lasts, in seconds
+const BAN_DURATION: i64 = 24 * 60 * 60;
+/// points for a block or header without valid proof of work,
+/// the one thing costly to get wrong by accident
+const INVALID_PROOF_OF_WORK: u32 = BAN_THRESHOLD;
+/// points for a block or header with another target than the
+/// rules give, out of order or built on an invalid block
+const INVALID_HEADER: u32 = 50;
+/// points for a block with valid proof of work whose transactions
+/// we reject, more likely a peer on other rules than an attack
+const INVALID_BLOCK_TRANSACTIONS: u32 = 50;
+/// points for a transaction that can never be valid
+pub const INVALID_TRANSACTION: u32 = 10;
+/// points for a frame that does not decode to a message
+pub const UNDECODABLE_FRAME: u32 = BAN_THRESHOLD;
+/// points for a message we did not ask for
+pub const UNSOLICITED: u32 = 20;
+
+/// Points for a transaction the mempool rejected with `error`. Low
+/// fees, a full mempool and missing inputs are not held against
+/// the peer, the transaction may be fine for other nodes
+pub fn transaction_penalty(error: &BtcError) -> u32 {
+    match error {
+        BtcError::InvalidTransaction
+        | BtcError::InvalidTransactionInput
+        | BtcError::InvalidTransactionOutput
+        | BtcError::InvalidSignature => INVALID_TRANSACTION,
+        _ => 0,
+    }
+}
+
+/// Points for a block or headers the blockchain rejected with `error`
+pub fn block_penalty(error: &BtcError) -> u32 {
+    match error {
+        BtcError::InvalidProofOfWork => INVALID_PROOF_OF_WORK,
+        BtcError::InvalidBlock | BtcError::InvalidBlockHeader => INVALID_HEADER,
+        BtcError::InvalidMerkleRoot
+        | BtcError::InvalidTransaction
+        | BtcError::InvalidTransactionInput
+        | BtcError::InvalidTransactionOutput
+        | BtcError::InvalidSignature => INVALID_BLOCK_TRANSACTIONS,
+        _ => 0,
+    }
+}
+
+/// Points for a connection that failed with `error`,
+/// broken connections and timeouts are not misbehavior
+pub fn frame_penalty(error: &NetworkError) -> u32 {
+    match error {
+        NetworkError::FrameTooLarge(_) | NetworkError::Decode(_) => UNDECODABLE_FRAME,
+        _ => 0,
+    }
+}
+
+/// Banned addresses and when their ban ends, seconds since the
+/// unix epoch. Saved to its file with every change
+#[derive(Default)]
+pub struct BanList {
+    path: Option<String>,
+    bans: HashMap<IpAddr, i64>,
+}
+
+impl BanList {
+    /// The bans saved in `path`, none if it does not exist yet
+    pub fn open(path: &str) -> Result<Self> {
+        let bans = if Path::new(path).exists() {
+            ciborium::from_reader(File::open(path)?)
+                .map_err(|e| anyhow!("Failed to deserialize bans: {}", e))?
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            path: Some(path.to_string()),
+            bans,
+        })
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let saved = File::create(path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| ciborium::into_writer(&self.bans, file).map_err(|e| e.to_string()));
+        if let Err(e) = saved {
+            eprintln!("failed to save bans to {}: {}", path, e);
+        }
+    }
+
+    /// Forget bans that ended
+    fn expire(&mut self) {
+        let now = Utc::now().timestamp();
+        let before = self.bans.len();
+        self.bans.retain(|_, until| *until > now);
+        if self.bans.len() != before {
+            self.save();
+        }
+    }
+
+    pub fn is_banned(&mut self, ip: &IpAddr) -> bool {
+        self.expire();
+        self.bans.contains_key(ip)
+    }
+
+    pub fn ban(&mut self, ip: IpAddr, until: i64) {
+        self.bans.insert(ip, until);
+        self.save();
+    }
+
+    /// The current bans, for the Bans message
+    pub fn list(&mut self) -> Vec<(String, i64)> {
+        self.expire();
+        self.bans
+            .iter()
+            .map(|(ip, until)| (ip.to_string(), *until))
+            .collect()
+    }
+
+    /// Lift all bans, returning them
+    pub fn clear(&mut self) -> Vec<(String, i64)> {
+        let lifted = self.list();
+        self.bans.clear();
+        self.save();
+        lifted
+    }
+}
+
+pub fn is_banned(ip: &IpAddr) -> bool {
+    BANS.lock().unwrap().is_banned(ip)
+}
+
+/// Misbehavior points a connection collected
+pub struct Score {
+    peer: String,
+    ip: IpAddr,
+    points: u32,
+}
+
+impl Score {
+    pub fn new(peer: String, ip: IpAddr) -> Self {
+        Self {
+            peer,
+            ip,
+            points: 0,
+        }
+    }
+
+    /// Hold `points` for `reason` against the peer. True if it crossed
+    /// the threshold and has to be disconnected, it is banned then
+    /// unless it is on this machine: local peers are our own wallets
+    /// and miners, and the admin commands must keep working
+    pub fn add(&mut self, points: u32, reason: &str) -> bool {
+        self.add_to(&mut BANS.lock().unwrap(), points, reason)
+    }
+
+    /// `add`, banning in `bans`
+    fn add_to(&mut self, bans: &mut BanList, points: u32, reason: &str) -> bool {
+        if points == 0 {
+            return false;
+        }
+        self.points += points;
+        println!(
+            "peer {} misbehaved: {}, score {}",
+            self.peer, reason, self.points
+        );
+        if self.points < BAN_THRESHOLD {
+            return false;
+        }
+        if !self.ip.is_loopback() {
+            let until = Utc::now().timestamp() + BAN_DURATION;
+            bans.ban(self.ip, until);
+            println!("banned {} until {}", self.ip, format_time(until));
+        }
+        true
+    }
+}
+
+fn format_time(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+/// Ask the node listening on `port` of this machine for its bans,
+/// lifting them if `clear`, and print them
+pub async fn admin(port: u16, clear: bool) -> Result<()> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    handler::version(false).await.handshake(&mut stream).await?;
+    let mut connection = Framed::new(stream, MessageCodec::default());
+    let message = if clear {
+        Message::ClearBans
+    } else {
+        Message::ListBans
+    };
+    let bans = match sync::request(&mut connection, message).await? {
+        Message::Bans(bans) => bans,
+        _ => return Err(anyhow!("unexpected response to ban command")),
+    };
+    if bans.is_empty() {
+        println!("no bans");
+    }
+    for (address, until) in bans {
+        if clear {
+            println!("lifted the ban on {}", address);
+        } else {
+            println!("{} banned until {}", address, format_time(until));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btclib::network::MAX_FRAME_SIZE;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn bans_once_the_threshold_is_crossed() {
+        let mut bans = BanList::default();
+        let peer = ip("192.0.2.10");
+        let mut score = Score::new("192.0.2.10:9000".to_string(), peer);
+        assert!(!score.add_to(&mut bans, 0, "nothing"));
+        assert!(!score.add_to(&mut bans, BAN_THRESHOLD - 1, "almost"));
+        assert!(!bans.is_banned(&peer));
+        assert!(score.add_to(&mut bans, 1, "enough"));
+        assert!(bans.is_banned(&peer));
+    }
+
+    #[test]
+    fn never_bans_this_machine() {
+        let mut bans = BanList::default();
+        let peer = ip("127.0.0.1");
+        let mut score = Score::new("127.0.0.1:9000".to_string(), peer);
+        assert!(score.add_to(&mut bans, BAN_THRESHOLD, "invalid block"));
+        assert!(!bans.is_banned(&peer));
+    }
+
+    #[test]
+    fn bans_expire() {
+        let mut bans = BanList::default();
+        let now = Utc::now().timestamp();
+        bans.ban(ip("192.0.2.20"), now - 1);
+        bans.ban(ip("192.0.2.21"), now + BAN_DURATION);
+        assert!(!bans.is_banned(&ip("192.0.2.20")));
+        assert!(bans.is_banned(&ip("192.0.2.21")));
+        assert_eq!(bans.list().len(), 1);
+        assert_eq!(bans.clear().len(), 1);
+        assert!(!bans.is_banned(&ip("192.0.2.21")));
+    }
+
+    #[test]
+    fn bans_survive_a_restart() {
+        // a name no other test run uses
+        let name = format!(
+            "bans-{}-{:x}.cbor",
+            std::process::id(),
+            rand::random::<u64>()
+        );
+        let path = std::env::temp_dir().join(name);
+        let path = path.to_str().unwrap();
+        let until = Utc::now().timestamp() + BAN_DURATION;
+        let mut bans = BanList::open(path).unwrap();
+        bans.ban(ip("192.0.2.30"), until);
+        bans.ban(ip("2001:db8::1"), until);
+
+        let mut reopened = BanList::open(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let mut list = reopened.list();
+        list.sort();
+        assert_eq!(
+            list,
+            vec![
+                ("192.0.2.30".to_string(), until),
+                ("2001:db8::1".to_string(), until)
+            ]
+        );
+    }
+
+    #[test]
+    fn penalties_match_the_error() {
+        assert_eq!(block_penalty(&BtcError::InvalidProofOfWork), BAN_THRESHOLD);
+        assert!(block_penalty(&BtcError::InvalidBlock) < BAN_THRESHOLD);
+        assert!(block_penalty(&BtcError::InvalidBlockHeader) < BAN_THRESHOLD);
+        assert!(block_penalty(&BtcError::InvalidTransaction) < BAN_THRESHOLD);
+        assert_eq!(block_penalty(&BtcError::MissingInput), 0);
+
+        assert_eq!(
+            transaction_penalty(&BtcError::InvalidSignature),
+            INVALID_TRANSACTION
+        );
+        for error in [
+            BtcError::MissingInput,
+            BtcError::FeeTooLow,
+            BtcError::MempoolFull,
+            BtcError::AlreadyInMempool,
+            BtcError::ReplacementSpendsConflict,
+        ] {
+            assert_eq!(transaction_penalty(&error), 0);
+        }
+
+        let frame = NetworkError::FrameTooLarge(MAX_FRAME_SIZE + 1);
+        assert_eq!(frame_penalty(&frame), UNDECODABLE_FRAME);
+        assert_eq!(frame_penalty(&NetworkError::Timeout), 0);
+    }
+}
@@ -0,0 +1,326 @@
+use btclib::error::NetworkError;
+use btclib::network::{
+    self, Message, MessageCodec, Version, MAX_BLOCKS, MAX_FRAME_SIZE, MAX_HEADERS, WRITE_TIMEOUT,
+};
+use btclib::sha256::Hash;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{self, timeout, Duration, Instant};
+use tokio_util::codec::Framed;
+
+use crate::{ban, peers, relay};
+use crate::{BANS, BLOCKCHAIN, NONCE, PEERS, RELAY};
+
+/// messages waiting to be written to one connection
+const SEND_QUEUE: usize = 100;
+/// how often every connection is pinged
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// a peer that leaves this many pings in a row
+/// unanswered is considered gone
+const MAX_MISSED_PINGS: u32 = 3;
+
+/// The Version this node announces, `relay` if it wants
+/// new transactions and blocks from the peer
+pub async fn version(relay: bool) -> Version {
+    let best_height = BLOCKCHAIN.read().await.block_height();
+    let user_agent = format!("btclib-node/{}", env!("CARGO_PKG_VERSION"));
+    Version {
+        nonce: *NONCE,
+        ..Version::new(best_height, user_agent, relay)
+    }
+}
+
+/// Serve a single peer (miner, wallet or node) until it disconnects.
+/// `outbound` connections are the ones we opened to other nodes.
+/// Returns false if the handshake did not go through
+pub async fn handle_connection(mut socket: TcpStream, peer: String, outbound: bool) -> bool {
+    let Ok(ip) = socket.peer_addr().map(|address| address.ip()) else {
+        return false;
+    };
+    if ban::is_banned(&ip) {
+        println!("{} is banned, closing connection", peer);
+        return false;
+    }
+    let mut score = ban::Score::new(peer.clone(), ip);
+    let version = match version(true).await.handshake(&mut socket).await {
+        Ok(version) => version,
+        Err(NetworkError::SelfConnection) => {
+            println!("{} is this node, closing connection", peer);
+            if outbound {
+                PEERS.remove(&peer);
+            }
+            return false;
+        }
+        Err(e) => {
+            println!("handshake with {} failed: {}", peer, e);
+            score.add(ban::frame_penalty(&e), "undecodable frame");
+            return false;
+        }
+    };
+    println!(
+        "handshake with {} done: {}, height {}, protocol version {}",
+        peer,
+        version.user_agent,
+        version.best_height,
+        version.negotiated()
+    );
+    if outbound {
+        peers::record_success(&peer);
+    }
+
+    // frames are limited to MAX_FRAME_SIZE, so a peer stalling in
+    // the middle of one ties up little more than its own connection
+    let (mut sink, mut stream) = Framed::new(socket, MessageCodec::default()).split();
+    // responses and relayed announcements share one writer
+    let (sender, mut receiver) = mpsc::channel::<Message>(SEND_QUEUE);
+    let writer = tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            let sent = timeout(WRITE_TIMEOUT, sink.send(message))
+                .await
+                .unwrap_or(Err(NetworkError::Timeout));
+            if let Err(e) = sent {
+                println!("failed to send message: {}, closing connection", e);
+                return;
+            }
+        }
+    });
+    // nodes want announcements, wallets and miners do not
+    if version.relay {
+        RELAY.insert(peer.clone(), sender.clone());
+    }
+    if outbound {
+        tokio::spawn(peers::discover(sender.clone()));
+    }
+
+    // the first tick completes right away, so the
+    // latency is known soon after connecting
+    let mut ping = time::interval(PING_INTERVAL);
+    // nonce of the Ping waiting for its Pong, and when it was sent
+    let mut pending: Option<(u64, Instant)> = None;
+    let mut missed = 0;
+    // asked for the ancestors of an orphan the peer sent,
+    // headers first, then the blocks
+    let mut asked_headers = false;
+    let mut asked_blocks = false;
+    loop {
+        let message = tokio::select! {
+            message = stream.next() => match message {
+                Some(Ok(message)) => message,
+                Some(Err(e)) => {
+                    println!("invalid message from peer: {}, closing connection", e);
+                    score.add(ban::frame_penalty(&e), "undecodable frame");
+                    break;
+                }
+                None => {
+                    println!("peer {} disconnected", peer);
+                    break;
+                }
+            },
+            _ = ping.tick() => {
+                if pending.is_some() {
+                    missed += 1;
+                    if missed >= MAX_MISSED_PINGS {
+                        println!("peer {} missed {} pings, disconnecting", peer, missed);
+                        break;
+                    }
+                }
+                let nonce = rand::random();
+                pending = Some((nonce, Instant::now()));
+                if sender.send(Message::Ping(nonce)).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        use Message::*;
+        let response = match message {
+            Ping(nonce) => Some(Pong(nonce)),
+            Pong(nonce) => {
+                // answers to older pings are ignored, the peer
+                // was too slow for them anyway
+                if let Some((expected, sent)) = pending {
+                    if nonce == expected {
+                        pending = None;
+                        missed = 0;
+                        peers::record_latency(&peer, sent.elapsed());
+                    }
+                }
+                None
+            }
+            FetchUTXOs(pubkey) => {
+                let blockchain = BLOCKCHAIN.read().await;
+                let utxos = blockchain
+                    .outputs_of(&pubkey)
+                    .map(|(outpoint, output, confirmed)| {
+                        let spent = blockchain.mempool().is_spent(&outpoint);
+                        (outpoint, output.clone(), spent, confirmed)
+                    })
+                    .collect();
+                Some(UTXOs(utxos))
+            }
+            SubmitTransaction(transaction) => match relay::transaction(transaction, None).await {
+                Ok(()) => Some(TransactionAccepted),
+                Err(e) => {
+                    if score.add(ban::transaction_penalty(&e), "invalid transaction") {
+                        break;
+                    }
+                    Some(TransactionRejected(e))
+                }
+            },
+            NewTransaction(transaction) => {
+                if let Err(e) = relay::transaction(transaction, Some(&peer)).await {
+                    if score.add(ban::transaction_penalty(&e), "invalid transaction") {
+                        break;
+                    }
+                }
+                None
+            }
+            FetchTemplate(pubkey) => {
+                let blockchain = BLOCKCHAIN.read().await;
+                Some(Template(blockchain.build_template(pubkey)))
+            }
+            ValidateTemplate(template) => {
+                let blockchain = BLOCKCHAIN.read().await;
+                let tip = blockchain
+                    .blocks()
+                    .last()
+                    .map(|block| block.hash())
+                    .unwrap_or(Hash::zero());
+                Some(TemplateValidity(template.header.prev_block_hash == tip))
+            }
+            SubmitTemplate(block) => {
+                if let Err(e) = relay::block(block, None).await {
+                    if score.add(ban::block_penalty(&e), "invalid block") {
+                        break;
+                    }
+                }
+                None
+            }
+            NewBlock(block) => match relay::block(block, Some(&peer)).await {
+                // the peer has blocks we are missing
+                Ok(added) if added.orphan && !asked_headers && !asked_blocks => {
+                    asked_headers = true;
+                    let locator = BLOCKCHAIN.read().await.locator();
+                    Some(GetHeaders(locator, MAX_HEADERS as u32))
+                }
+                Ok(_) => None,
+                Err(e) => {
+                    if score.add(ban::block_penalty(&e), "invalid block") {
+                        break;
+                    }
+                    None
+                }
+            },
+            Headers(headers) if asked_headers => {
+                asked_headers = false;
+                let blockchain = BLOCKCHAIN.read().await;
+                let checked = blockchain.check_headers(&headers).map(|work| {
+                    // a branch with less work is not worth fetching
+                    if work <= blockchain.chain_work() {
+                        return vec![];
+                    }
+                    headers
+                        .iter()
+                        .map(|header| header.hash())
+                        .filter(|hash| blockchain.find_block(hash).is_none())
+                        .take(MAX_BLOCKS)
+                        .collect()
+                });
+                drop(blockchain);
+                match checked {
+                    Ok(hashes) if hashes.is_empty() => None,
+                    Ok(hashes) => {
+                        asked_blocks = true;
+                        Some(GetBlocks(hashes))
+                    }
+                    Err(e) => {
+                        if score.add(ban::block_penalty(&e), "invalid headers") {
+                            break;
+                        }
+                        None
+                    }
+                }
+            }
+            Blocks(blocks) if asked_blocks => {
+                asked_blocks = false;
+                let mut failed = None;
+                for block in blocks {
+                    if let Err(e) = relay::block(block, Some(&peer)).await {
+                        failed = Some(e);
+                        break;
+                    }
+                }
+                if let Some(e) = failed {
+                    if score.add(ban::block_penalty(&e), "invalid block") {
+                        break;
+                    }
+                }
+                None
+            }
+            DiscoverNodes => Some(NodeList(peers::good_peers())),
+            NodeList(nodes) if outbound => {
+                peers::learn(&peer, nodes);
+                None
+            }
+            FetchBlock(height) => {
+                let blockchain = BLOCKCHAIN.read().await;
+                let block = blockchain.blocks().nth(height).cloned();
+                if block.is_none() {
+                    println!("no block at height {}", height);
+                }
+                block.map(NewBlock)
+            }
+            GetHeaders(locator, count) => {
+                let blockchain = BLOCKCHAIN.read().await;
+                let count = (count as usize).min(MAX_HEADERS);
+                Some(Headers(blockchain.headers_after(&locator, count)))
+            }
+            GetBlocks(hashes) => {
+                let blockchain = BLOCKCHAIN.read().await;
+                let blocks = hashes
+                    .iter()
+                    .take(MAX_BLOCKS)
+                    .filter_map(|hash| blockchain.find_block(hash).cloned());
+                // the peer asks again for the ones left out
+                Some(Blocks(network::blocks_within(blocks, MAX_FRAME_SIZE)))
+            }
+            // only the operator may see and lift bans
+            ListBans if ip.is_loopback() => Some(Bans(BANS.lock().unwrap().list())),
+            ClearBans if ip.is_loopback() => {
+                let lifted = BANS.lock().unwrap().clear();
+                println!("lifted {} bans", lifted.len());
+                Some(Bans(lifted))
+            }
+            Version(_)
+            | Verack
+            | UTXOs(_)
+            | TransactionAccepted
+            | TransactionRejected(_)
+            | Template(_)
+            | TemplateValidity(_)
+            | NodeList(_)
+            | Headers(_)
+            | Blocks(_)
+            | ListBans
+            | ClearBans
+            | Bans(_) => {
+                if score.add(ban::UNSOLICITED, "unsolicited message") {
+                    break;
+                }
+                None
+            }
+        };
+
+        if let Some(response) = response {
+            if sender.send(response).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    RELAY.remove(&peer);
+    writer.abort();
+    true
+}
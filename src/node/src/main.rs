@@ -1,3 +1,150 @@
-fn main() {
-    println!("Hello, world!");
+use anyhow::Result;
+use btclib::network::Message;
+use btclib::types::{Blockchain, Mempool};
+use btclib::util::Saveable;
+use clap::Parser;
+use dashmap::{DashMap, DashSet};
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, RwLock};
+
+mod ban;
+mod handler;
+mod peers;
+mod relay;
+mod sync;
+mod util;
+
+/// The blockchain this node keeps, shared by all connections
+pub static BLOCKCHAIN: LazyLock<RwLock<Blockchain>> =
+    LazyLock::new(|| RwLock::new(Blockchain::new()));
+
+/// Addresses of the nodes we have an outbound connection to
+pub static NODES: LazyLock<DashSet<String>> = LazyLock::new(DashSet::new);
+
+/// Every node address we know about
+pub static PEERS: LazyLock<DashMap<String, peers::PeerInfo>> = LazyLock::new(DashMap::new);
+
+/// Connections to other nodes, new transactions and blocks are announced to them
+pub static RELAY: LazyLock<DashMap<String, mpsc::Sender<Message>>> = LazyLock::new(DashMap::new);
+
+/// Transactions and blocks already received, so they are relayed only once
+pub static SEEN: LazyLock<Mutex<relay::SeenSet>> =
+    LazyLock::new(|| Mutex::new(relay::SeenSet::new(SEEN_CAPACITY)));
+
+/// Addresses not allowed to connect for a while after misbehaving
+pub static BANS: LazyLock<Mutex<ban::BanList>> =
+    LazyLock::new(|| Mutex::new(ban::BanList::default()));
+
+/// Sent in every Version, to recognize connections to ourselves
+pub static NONCE: LazyLock<u64> = LazyLock::new(rand::random);
+
+/// number of transaction and block hashes remembered
+const SEEN_CAPACITY: usize = 10_000;
+
+#[derive(Parser)]
+#[command(about = "A btclib node")]
+struct Cli {
+    /// port to listen on
+    #[arg(short, long, default_value_t = 9000)]
+    port: u16,
+    /// file to load the blockchain from and save it to
+    #[arg(short, long, default_value = "./blockchain.cbor")]
+    blockchain_file: String,
+    /// file to load pending transactions from and save them to
+    #[arg(long, default_value = "./mempool.cbor")]
+    mempool_file: String,
+    /// file to load known peers from and save them to
+    #[arg(long, default_value = "./peers.cbor")]
+    peers_file: String,
+    /// file to load banned addresses from and save them to
+    #[arg(long, default_value = "./bans.cbor")]
+    bans_file: String,
+    /// print the addresses the node running on --port banned and exit
+    #[arg(long)]
+    list_bans: bool,
+    /// lift all bans of the node running on --port and exit
+    #[arg(long, conflicts_with = "list_bans")]
+    clear_bans: bool,
+    /// number of outbound connections to keep
+    #[arg(short, long, default_value_t = 8)]
+    outbound: usize,
+    /// seed addresses of other nodes
+    nodes: Vec<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    if cli.list_bans || cli.clear_bans {
+        return ban::admin(cli.port, cli.clear_bans).await;
+    }
+
+    if Path::new(&cli.peers_file).exists() {
+        if let Err(e) = peers::load(&cli.peers_file) {
+            println!("failed to load peers from {}: {}", cli.peers_file, e);
+        }
+    }
+    for node in cli.nodes {
+        peers::add(node);
+    }
+    println!("{} known peers", PEERS.len());
+    let mut bans = ban::BanList::open(&cli.bans_file)?;
+    println!("{} banned addresses", bans.list().len());
+    *BANS.lock().unwrap() = bans;
+
+    if Path::new(&cli.blockchain_file).exists() {
+        println!("loading blockchain from {}", cli.blockchain_file);
+        let blockchain = Blockchain::load_from_file(&cli.blockchain_file)?;
+        println!("blockchain loaded, height {}", blockchain.block_height());
+        *BLOCKCHAIN.write().await = blockchain;
+    } else {
+        println!("no blockchain file found, starting a new blockchain");
+    }
+
+    sync::initial_block_download().await;
+
+    // after syncing, so transactions spending outputs of the
+    // downloaded blocks are valid again
+    if Path::new(&cli.mempool_file).exists() {
+        match Mempool::load_from_file(&cli.mempool_file) {
+            Ok(mempool) => {
+                let mut blockchain = BLOCKCHAIN.write().await;
+                let dropped = blockchain.restore_mempool(mempool);
+                println!(
+                    "mempool loaded, {} transactions, {} no longer valid",
+                    blockchain.mempool().len(),
+                    dropped
+                );
+            }
+            Err(e) => println!("failed to load mempool from {}: {}", cli.mempool_file, e),
+        }
+    }
+
+    tokio::spawn(util::save(
+        cli.blockchain_file.clone(),
+        cli.mempool_file.clone(),
+    ));
+    tokio::spawn(util::cleanup());
+    tokio::spawn(peers::maintain(cli.peers_file.clone(), cli.outbound));
+
+    let address = format!("0.0.0.0:{}", cli.port);
+    let listener = TcpListener::bind(&address).await?;
+    println!("listening on {}", address);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, peer) = accepted?;
+                println!("new connection from {}", peer);
+                tokio::spawn(handler::handle_connection(socket, peer.to_string(), false));
+            }
+            _ = tokio::signal::ctrl_c() => {
+                println!("shutting down");
+                util::save_all(&cli.blockchain_file, &cli.mempool_file).await;
+                return Ok(());
+            }
+        }
+    }
 }
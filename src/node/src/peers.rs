@@ -0,0 +1,297 @@
+use anyhow::{anyhow, Result};
+use btclib::network::Message;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{self, timeout, Duration, Instant};
+
+use crate::handler;
+use crate::{NODES, PEERS};
+
+/// how often missing outbound connections are opened
+const MAINTAIN_INTERVAL: Duration = Duration::from_secs(10);
+/// how often a connected peer is asked for the nodes it knows
+const DISCOVER_INTERVAL: Duration = Duration::from_secs(60);
+/// how long to wait for a peer to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// wait before retrying an address after its first failure,
+/// doubled with every further failure
+const BASE_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+/// addresses that never worked are forgotten after this many failures
+const MAX_FAILURES: u32 = 10;
+/// upper bound on the addresses we keep
+const MAX_KNOWN_PEERS: usize = 1000;
+/// upper bound on the addresses handed out for DiscoverNodes
+const MAX_NODE_LIST: usize = 100;
+
+/// What we know about a peer address
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PeerInfo {
+    /// when we last got an answer from it, seconds since the unix epoch
+    pub last_success: Option<i64>,
+    /// failed attempts since the last success
+    pub failures: u32,
+    /// round trip time of the last Ping answered
+    #[serde(default)]
+    pub latency: Option<Duration>,
+    /// not before this to connect again
+    #[serde(skip)]
+    retry_at: Option<Instant>,
+}
+
+impl PeerInfo {
+    fn backoff(&self) -> Duration {
+        BASE_BACKOFF
+            .saturating_mul(2u32.saturating_pow(self.failures.saturating_sub(1)))
+            .min(MAX_BACKOFF)
+    }
+}
+
+/// Read the peer table saved by `save`
+pub fn load(path: &str) -> Result<()> {
+    let peers: HashMap<String, PeerInfo> = ciborium::from_reader(File::open(path)?)
+        .map_err(|e| anyhow!("Failed to deserialize peers: {}", e))?;
+    for (address, info) in peers {
+        PEERS.insert(address, info);
+    }
+    Ok(())
+}
+
+pub fn save(path: &str) -> Result<()> {
+    let peers: HashMap<String, PeerInfo> = PEERS
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect();
+    ciborium::into_writer(&peers, File::create(path)?)
+        .map_err(|e| anyhow!("Failed to serialize peers: {}", e))
+}
+
+/// A host and a port
+fn is_valid(address: &str) -> bool {
+    address
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+}
+
+/// Learn about `address` unless it looks invalid or the table is full
+pub fn add(address: String) {
+    if !is_valid(&address) || PEERS.contains_key(&address) || PEERS.len() >= MAX_KNOWN_PEERS {
+        return;
+    }
+    PEERS.insert(address, PeerInfo::default());
+}
+
+/// Addresses that answered us before, for DiscoverNodes
+pub fn good_peers() -> Vec<String> {
+    PEERS
+        .iter()
+        .filter(|entry| entry.last_success.is_some())
+        .take(MAX_NODE_LIST)
+        .map(|entry| entry.key().clone())
+        .collect()
+}
+
+/// Take in the NodeList `peer` answered with
+pub fn learn(peer: &str, nodes: Vec<String>) {
+    record_success(peer);
+    for node in nodes {
+        add(node);
+    }
+}
+
+/// `address` answered a Ping after `latency`
+pub fn record_latency(address: &str, latency: Duration) {
+    record_success(address);
+    if let Some(mut info) = PEERS.get_mut(address) {
+        info.latency = Some(latency);
+    }
+}
+
+/// `address` answered, it is no longer backing off
+pub fn record_success(address: &str) {
+    if let Some(mut info) = PEERS.get_mut(address) {
+        info.last_success = Some(Utc::now().timestamp());
+        info.failures = 0;
+        info.retry_at = None;
+    }
+}
+
+fn record_failure(address: &str) {
+    let forget = match PEERS.get_mut(address) {
+        Some(mut info) => {
+            info.failures += 1;
+            info.retry_at = Some(Instant::now() + info.backoff());
+            info.last_success.is_none() && info.failures >= MAX_FAILURES
+        }
+        None => false,
+    };
+    if forget {
+        PEERS.remove(address);
+    }
+}
+
+/// Addresses to connect to next, see `select`
+fn candidates(count: usize) -> Vec<String> {
+    let peers = PEERS
+        .iter()
+        .filter(|entry| !NODES.contains(entry.key()))
+        .map(|entry| (entry.key().clone(), entry.value().clone()));
+    select(peers, Instant::now(), count)
+}
+
+/// The `count` best of `peers` not backing off at `now`: the ones
+/// that worked before and failed least first, the fastest of those
+/// first and the ones never pinged after them
+fn select(
+    peers: impl Iterator<Item = (String, PeerInfo)>,
+    now: Instant,
+    count: usize,
+) -> Vec<String> {
+    let mut candidates: Vec<(bool, u32, Duration, String)> = peers
+        .filter(|(_, info)| info.retry_at.is_none_or(|retry_at| retry_at <= now))
+        .map(|(address, info)| {
+            (
+                info.last_success.is_none(),
+                info.failures,
+                info.latency.unwrap_or(Duration::MAX),
+                address,
+            )
+        })
+        .collect();
+    candidates.sort();
+    candidates
+        .into_iter()
+        .take(count)
+        .map(|(_, _, _, address)| address)
+        .collect()
+}
+
+/// Keep `outbound` connections to peers open and save
+/// the peer table to `path` while doing so
+pub async fn maintain(path: String, outbound: usize) {
+    let mut interval = time::interval(MAINTAIN_INTERVAL);
+    loop {
+        interval.tick().await;
+        for address in candidates(outbound.saturating_sub(NODES.len())) {
+            NODES.insert(address.clone());
+            tokio::spawn(connect(address));
+        }
+        if let Err(e) = save(&path) {
+            eprintln!("failed to save peers to {}: {}", path, e);
+        }
+    }
+}
+
+/// An outbound connection, served like any other until it fails.
+/// Only a peer we could not connect to or shake hands with counts
+/// as failing, a session that ends is not held against it
+async fn connect(address: String) {
+    println!("connecting to peer {}", address);
+    let established = match timeout(CONNECT_TIMEOUT, TcpStream::connect(&address)).await {
+        Ok(Ok(stream)) => handler::handle_connection(stream, address.clone(), true).await,
+        Ok(Err(e)) => {
+            println!("could not connect to {}: {}", address, e);
+            false
+        }
+        Err(_) => {
+            println!("connecting to {} timed out", address);
+            false
+        }
+    };
+    NODES.remove(&address);
+    if established {
+        println!("lost peer {}", address);
+    } else {
+        record_failure(&address);
+    }
+}
+
+/// Ask an outbound peer for the nodes it knows now and then,
+/// until its connection is gone
+pub async fn discover(sender: mpsc::Sender<Message>) {
+    let mut interval = time::interval(DISCOVER_INTERVAL);
+    loop {
+        interval.tick().await;
+        if sender.send(Message::DiscoverNodes).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(last_success: Option<i64>, failures: u32) -> PeerInfo {
+        PeerInfo {
+            last_success,
+            failures,
+            ..PeerInfo::default()
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        assert_eq!(info(None, 1).backoff(), BASE_BACKOFF);
+        assert_eq!(info(None, 2).backoff(), BASE_BACKOFF * 2);
+        assert_eq!(info(None, 4).backoff(), BASE_BACKOFF * 8);
+        assert_eq!(info(None, 20).backoff(), MAX_BACKOFF);
+        assert_eq!(info(None, u32::MAX).backoff(), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn selects_working_peers_first() {
+        let now = Instant::now();
+        let backing_off = PeerInfo {
+            retry_at: Some(now + BASE_BACKOFF),
+            ..info(Some(1), 0)
+        };
+        let retry_due = PeerInfo {
+            retry_at: Some(now),
+            ..info(Some(1), 2)
+        };
+        let peers = vec![
+            ("never:1".to_string(), info(None, 0)),
+            ("flaky:1".to_string(), retry_due),
+            ("waiting:1".to_string(), backing_off),
+            ("good:1".to_string(), info(Some(1), 0)),
+        ];
+        assert_eq!(
+            select(peers.clone().into_iter(), now, 10),
+            vec!["good:1", "flaky:1", "never:1"]
+        );
+        assert_eq!(select(peers.into_iter(), now, 1), vec!["good:1"]);
+    }
+
+    #[test]
+    fn selects_faster_peers_first() {
+        let latency = |millis| PeerInfo {
+            latency: Some(Duration::from_millis(millis)),
+            ..info(Some(1), 0)
+        };
+        let peers = vec![
+            ("slow:1".to_string(), latency(300)),
+            ("unpinged:1".to_string(), info(Some(1), 0)),
+            ("fast:1".to_string(), latency(20)),
+        ];
+        assert_eq!(
+            select(peers.into_iter(), Instant::now(), 10),
+            vec!["fast:1", "slow:1", "unpinged:1"]
+        );
+    }
+
+    #[test]
+    fn accepts_only_host_and_port() {
+        assert!(is_valid("127.0.0.1:9000"));
+        assert!(is_valid("node.example:9000"));
+        assert!(is_valid("[::1]:9000"));
+        assert!(!is_valid("127.0.0.1"));
+        assert!(!is_valid(":9000"));
+        assert!(!is_valid("127.0.0.1:port"));
+        assert!(!is_valid("127.0.0.1:70000"));
+    }
+}
@@ -0,0 +1,224 @@
+use btclib::error::Result;
+use btclib::network::Message;
+use btclib::sha256::Hash;
+use btclib::types::{AddedBlock, Block, Transaction};
+use std::collections::{HashSet, VecDeque};
+
+use crate::{BLOCKCHAIN, RELAY, SEEN};
+
+/// Hashes of the most recent transactions and blocks,
+/// the oldest is forgotten once `capacity` is reached
+pub struct SeenSet {
+    order: VecDeque<Hash>,
+    hashes: HashSet<Hash>,
+    capacity: usize,
+}
+
+impl SeenSet {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            order: VecDeque::with_capacity(capacity),
+            hashes: HashSet::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    /// Remember `hash`, false if it was already known
+    pub fn insert(&mut self, hash: Hash) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Send `message` to every connected node except `from`
+fn broadcast(message: Message, from: Option<&str>) {
+    for connection in RELAY.iter() {
+        if Some(connection.key().as_str()) == from {
+            continue;
+        }
+        // a slow peer misses announcements rather than holding us up
+        if connection.value().try_send(message.clone()).is_err() {
+            println!("could not relay to {}, dropping it", connection.key());
+        }
+    }
+}
+
+/// Add a transaction submitted by a wallet (`from` is None)
+/// or announced by a node to the mempool and announce it
+/// to the other nodes. Fails if the mempool rejected it.
+/// Only accepted transactions are remembered as seen, one
+/// rejected for now, say before its parent, may come again
+pub async fn transaction(transaction: Transaction, from: Option<&str>) -> Result<()> {
+    let hash = transaction.hash();
+    if SEEN.lock().unwrap().contains(&hash) {
+        return Ok(());
+    }
+    let mut blockchain = BLOCKCHAIN.write().await;
+    match blockchain.add_to_mempool(transaction.clone()) {
+        Ok(()) => {
+            if SEEN.lock().unwrap().insert(hash) {
+                broadcast(Message::NewTransaction(transaction), from);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            println!("transaction rejected: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Add a block mined by a miner (`from` is None) or announced by
+/// a node to the blockchain and announce it to the other nodes.
+/// Fails if the block is invalid. Only a block that went on the
+/// active chain or a side branch is remembered as seen and announced,
+/// an orphan may well be made up and is left to the caller
+pub async fn block(block: Block, from: Option<&str>) -> Result<AddedBlock> {
+    let hash = block.hash();
+    if SEEN.lock().unwrap().contains(&hash) {
+        return Ok(AddedBlock::default());
+    }
+    let mut blockchain = BLOCKCHAIN.write().await;
+    match blockchain.add_block(block.clone()) {
+        Ok(added) => {
+            report(hash, &added);
+            if added.accepted {
+                println!("block accepted, height {}", blockchain.block_height());
+                if SEEN.lock().unwrap().insert(hash) {
+                    broadcast(Message::NewBlock(block), from);
+                }
+            }
+            Ok(added)
+        }
+        Err(e) => {
+            println!("block rejected: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Log what adding the block `hash` did beyond connecting it
+pub fn report(hash: Hash, added: &AddedBlock) {
+    if added.orphan {
+        println!("parent of block {} is unknown, keeping it as orphan", hash);
+    }
+    for reorg in &added.reorgs {
+        println!(
+            "reorganized at height {}: disconnected {} blocks, connected {}",
+            reorg.height, reorg.disconnected, reorg.connected
+        );
+        if reorg.dropped > 0 {
+            println!(
+                "dropped {} transactions of the disconnected blocks",
+                reorg.dropped
+            );
+        }
+    }
+    for (orphan, e) in &added.rejected_orphans {
+        println!("orphan block {} rejected: {}", orphan, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btclib::crypto::{PrivateKey, Signature};
+    use btclib::error::BtcError;
+    use btclib::types::{BlockHeader, OutPoint, SigHash, TransactionInput, TransactionOutput};
+    use btclib::util::MerkleRoot;
+    use btclib::MIN_TARGET;
+    use chrono::Utc;
+
+    fn spend(outpoint: OutPoint, value: u64, key: &PrivateKey) -> Transaction {
+        let outputs = vec![TransactionOutput {
+            value,
+            pubkey: key.public_key(),
+        }];
+        let hash = Transaction::signature_hash(&[outpoint], &outputs, 0, SigHash::ALL).unwrap();
+        let input = TransactionInput {
+            prev_output: outpoint,
+            signature: Signature::sign(&hash, key),
+            sighash: SigHash::ALL,
+        };
+        Transaction::new(vec![input], outputs)
+    }
+
+    #[tokio::test]
+    async fn accepts_child_once_its_parent_is_in() {
+        let key = PrivateKey::new_key();
+        let mut blockchain = BLOCKCHAIN.write().await;
+        let coinbase = Transaction::coinbase(
+            0,
+            vec![TransactionOutput {
+                value: blockchain.calculate_block_reward(),
+                pubkey: key.public_key(),
+            }],
+        );
+        let value = coinbase.outputs[0].value;
+        let outpoint = OutPoint::new(coinbase.hash(), 0);
+        let transactions = vec![coinbase];
+        let mut genesis = Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(&transactions),
+                blockchain.target(),
+            ),
+            transactions,
+        );
+        while !genesis.header.mine(1_000) {}
+        blockchain.add_block(genesis).unwrap();
+        drop(blockchain);
+
+        let parent = spend(outpoint, value - 1_000, &key);
+        let child = spend(OutPoint::new(parent.hash(), 0), value - 2_000, &key);
+        assert!(matches!(
+            transaction(child.clone(), None).await,
+            Err(BtcError::MissingInput)
+        ));
+        transaction(parent, None).await.unwrap();
+        transaction(child.clone(), None).await.unwrap();
+        assert!(BLOCKCHAIN.read().await.mempool().contains(&child.hash()));
+        assert!(SEEN.lock().unwrap().contains(&child.hash()));
+    }
+
+    #[tokio::test]
+    async fn neither_remembers_nor_announces_orphans() {
+        let key = PrivateKey::new_key();
+        let transactions = vec![Transaction::coinbase(
+            1,
+            vec![TransactionOutput {
+                value: 1,
+                pubkey: key.public_key(),
+            }],
+        )];
+        let mut orphan = Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                Hash::hash_bytes(b"unknown parent"),
+                MerkleRoot::calculate(&transactions),
+                MIN_TARGET,
+            ),
+            transactions,
+        );
+        while !orphan.header.mine(1_000) {}
+
+        let added = block(orphan.clone(), None).await.unwrap();
+        assert!(added.orphan && !added.accepted);
+        assert!(!SEEN.lock().unwrap().contains(&orphan.hash()));
+    }
+}
@@ -0,0 +1,390 @@
+use anyhow::{anyhow, Result};
+use btclib::error::BtcError;
+use btclib::network::{Message, MessageCodec, MAX_BLOCKS, MAX_HEADERS};
+use btclib::sha256::Hash;
+use btclib::types::{Block, BlockHeader, HeaderCheck};
+use btclib::U256;
+use futures::{SinkExt, StreamExt};
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{timeout, Duration, Instant};
+use tokio_util::codec::Framed;
+
+use crate::{ban, handler, peers, relay};
+use crate::{BLOCKCHAIN, PEERS};
+
+/// how long to wait for a peer to connect or answer
+const SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+/// print progress every this many blocks
+const PROGRESS_INTERVAL: usize = 100;
+/// peers this many times slower than the fastest one fetch the
+/// blocks needed last, so they do not hold up connecting the others
+const SLOW_PEER_FACTOR: u32 = 2;
+/// chunks of `MAX_BLOCKS` fetched ahead of the block being added,
+/// which bounds the blocks held waiting for their parent
+const DOWNLOAD_WINDOW: usize = 16;
+
+/// A connection past the handshake
+pub type Connection = Framed<TcpStream, MessageCodec>;
+
+/// Send `message` and wait for the answer,
+/// answering the peer's pings meanwhile
+pub async fn request(connection: &mut Connection, message: Message) -> Result<Message> {
+    timeout(SYNC_TIMEOUT, async {
+        connection.send(message).await?;
+        loop {
+            match connection
+                .next()
+                .await
+                .ok_or_else(|| anyhow!("peer closed the connection"))??
+            {
+                Message::Ping(nonce) => connection.send(Message::Pong(nonce)).await?,
+                response => return Ok(response),
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("peer did not answer in time"))?
+}
+
+/// Connect to `peer`, learn its height from the handshake
+/// and ping it to measure its latency
+async fn probe(peer: &str) -> Result<(Connection, u64, Duration)> {
+    let mut stream = timeout(SYNC_TIMEOUT, TcpStream::connect(peer))
+        .await
+        .map_err(|_| anyhow!("connecting timed out"))??;
+    if ban::is_banned(&stream.peer_addr()?.ip()) {
+        return Err(anyhow!("peer is banned"));
+    }
+    let version = handler::version(false).await.handshake(&mut stream).await?;
+    let mut connection = Framed::new(stream, MessageCodec::default());
+    let nonce = rand::random();
+    let sent = Instant::now();
+    match request(&mut connection, Message::Ping(nonce)).await? {
+        Message::Pong(answer) if answer == nonce => {}
+        _ => return Err(anyhow!("unexpected response to Ping")),
+    }
+    let latency = sent.elapsed();
+    peers::record_latency(peer, latency);
+    Ok((connection, version.best_height, latency))
+}
+
+/// Fetch the headers of `peer`'s chain past the fork from our active
+/// chain, up to the `best_height` it announced, checking every batch
+/// before asking for the next. Returns them and the work of that
+/// chain, headers breaking the rules are held against the peer, see
+/// `ban::block_penalty`
+async fn download_headers(
+    peer: &str,
+    connection: &mut Connection,
+    best_height: u64,
+) -> Result<(Vec<BlockHeader>, U256)> {
+    let ip = connection.get_ref().peer_addr()?.ip();
+    let mut score = ban::Score::new(peer.to_string(), ip);
+    let mut headers: Vec<BlockHeader> = vec![];
+    let mut check: Option<HeaderCheck> = None;
+    loop {
+        let mut locator = BLOCKCHAIN.read().await.locator();
+        if let Some(last) = headers.last() {
+            locator.insert(0, last.hash());
+        }
+        let mut batch =
+            match request(connection, Message::GetHeaders(locator, MAX_HEADERS as u32)).await? {
+                Message::Headers(batch) => batch,
+                _ => return Err(anyhow!("unexpected response to GetHeaders")),
+            };
+        // the peer's chain changed since the last batch
+        if let (Some(last), Some(first)) = (headers.last(), batch.first()) {
+            if first.prev_block_hash != last.hash() {
+                return Err(anyhow!("peer switched branches"));
+            }
+        }
+        let Some(first) = batch.first() else {
+            break;
+        };
+        let check = match &mut check {
+            Some(check) => check,
+            None => {
+                let started = BLOCKCHAIN.read().await.header_check(&first.prev_block_hash);
+                // the peer answered a locator with a block we do not know
+                let Some(started) = started else {
+                    let e = BtcError::InvalidBlockHeader;
+                    score.add(ban::block_penalty(&e), "invalid headers");
+                    return Err(e.into());
+                };
+                check.insert(started)
+            }
+        };
+        // blocks found since the handshake wait for the next sync
+        let left = best_height.saturating_sub(check.height()) as usize;
+        let done = batch.len() < MAX_HEADERS || batch.len() >= left;
+        batch.truncate(left);
+        if let Err(e) = check.extend(&batch) {
+            score.add(ban::block_penalty(&e), "invalid headers");
+            return Err(e.into());
+        }
+        headers.extend(batch);
+        println!("received {} headers from {}", headers.len(), peer);
+        if done {
+            break;
+        }
+    }
+    let work = check.map_or_else(U256::zero, |check| check.work());
+    Ok((headers, work))
+}
+
+/// Chunks of block hashes waiting to be fetched, with
+/// their place in the download
+type Queue = Arc<Mutex<VecDeque<(usize, Vec<Hash>)>>>;
+
+/// Fetch chunks from `queue` from `peer` until the queue is empty,
+/// from its front or, for a `slow` peer, its back, but no further
+/// than `DOWNLOAD_WINDOW` chunks past the one `window` says is being
+/// added. The part of a chunk the peer left out, say because the
+/// blocks did not fit in one frame, goes back to the front. A peer
+/// that serves none of a chunk stops
+async fn fetch_blocks(
+    peer: String,
+    mut connection: Connection,
+    slow: bool,
+    queue: Queue,
+    window: Arc<watch::Sender<usize>>,
+    sender: mpsc::Sender<(String, IpAddr, Vec<Block>)>,
+) {
+    let Ok(ip) = connection.get_ref().peer_addr().map(|address| address.ip()) else {
+        return;
+    };
+    let mut moved = window.subscribe();
+    loop {
+        let next = {
+            let mut queue = queue.lock().unwrap();
+            if queue.is_empty() {
+                return;
+            }
+            let limit = *moved.borrow_and_update() + DOWNLOAD_WINDOW;
+            let within = |(index, _): &(usize, Vec<Hash>)| *index < limit;
+            let position = if slow {
+                queue.iter().rposition(within)
+            } else {
+                queue.iter().position(within)
+            };
+            position.and_then(|position| queue.remove(position))
+        };
+        let Some((index, chunk)) = next else {
+            // what is left is too far ahead
+            if moved.changed().await.is_err() {
+                return;
+            }
+            continue;
+        };
+        let blocks = match request(&mut connection, Message::GetBlocks(chunk.clone())).await {
+            Ok(Message::Blocks(blocks)) => blocks,
+            Ok(_) => {
+                println!("unexpected response to GetBlocks from {}", peer);
+                vec![]
+            }
+            Err(e) => {
+                println!("fetching blocks from {} failed: {}", peer, e);
+                vec![]
+            }
+        };
+        let blocks: Vec<Block> = blocks
+            .into_iter()
+            .filter(|block| chunk.contains(&block.hash()))
+            .collect();
+        let missing: Vec<Hash> = chunk
+            .into_iter()
+            .filter(|hash| !blocks.iter().any(|block| block.hash() == *hash))
+            .collect();
+        let served = !blocks.is_empty();
+        if served && sender.send((peer.clone(), ip, blocks)).await.is_err() {
+            return;
+        }
+        if !missing.is_empty() {
+            queue.lock().unwrap().push_front((index, missing));
+            // the others may be waiting for it
+            window.send_modify(|_| {});
+        }
+        if !served {
+            return;
+        }
+    }
+}
+
+/// Download the blocks of `headers` from all `peers` at once,
+/// `MAX_BLOCKS` per request, and add them to the blockchain in
+/// order. Faster peers get the blocks needed first, see
+/// `SLOW_PEER_FACTOR`. An invalid block is held against its server
+async fn download_blocks(
+    headers: &[BlockHeader],
+    peers: Vec<(String, Connection, Duration)>,
+) -> Result<()> {
+    let hashes: Vec<Hash> = headers.iter().map(|header| header.hash()).collect();
+    println!(
+        "downloading {} blocks from {} peers",
+        hashes.len(),
+        peers.len()
+    );
+    let queue: Queue = Arc::new(Mutex::new(
+        hashes
+            .chunks(MAX_BLOCKS)
+            .map(<[Hash]>::to_vec)
+            .enumerate()
+            .collect(),
+    ));
+    let fastest = peers
+        .iter()
+        .map(|(_, _, latency)| *latency)
+        .min()
+        .unwrap_or_default();
+    let (sender, mut receiver) = mpsc::channel(DOWNLOAD_WINDOW);
+    // the chunk of the block being added
+    let window = Arc::new(watch::Sender::new(0));
+    let workers: Vec<_> = peers
+        .into_iter()
+        .map(|(peer, connection, latency)| {
+            let slow = latency > fastest.saturating_mul(SLOW_PEER_FACTOR);
+            tokio::spawn(fetch_blocks(
+                peer,
+                connection,
+                slow,
+                queue.clone(),
+                window.clone(),
+                sender.clone(),
+            ))
+        })
+        .collect();
+    // the channel closes once every worker is done
+    drop(sender);
+
+    // blocks that arrived before their parent, with who sent
+    // them, no more than the window holds
+    let mut received: HashMap<Hash, (Block, String, IpAddr)> = HashMap::new();
+    let mut result = Ok(());
+    for (index, hash) in hashes.iter().enumerate() {
+        if index % MAX_BLOCKS == 0 {
+            window.send_replace(index / MAX_BLOCKS);
+        }
+        let (block, peer, ip) = loop {
+            if let Some(block) = received.remove(hash) {
+                break block;
+            }
+            match receiver.recv().await {
+                Some((peer, ip, blocks)) => {
+                    for block in blocks {
+                        received.insert(block.hash(), (block, peer.clone(), ip));
+                    }
+                }
+                None => return Err(anyhow!("no peer served block {}", hash)),
+            }
+        };
+        let mut blockchain = BLOCKCHAIN.write().await;
+        match blockchain.add_block(block) {
+            Ok(added) => relay::report(*hash, &added),
+            Err(e) => {
+                ban::Score::new(peer, ip).add(ban::block_penalty(&e), "invalid block");
+                result = Err(e.into());
+                break;
+            }
+        }
+        let downloaded = index + 1;
+        if downloaded % PROGRESS_INTERVAL == 0 || downloaded == hashes.len() {
+            println!(
+                "downloaded {}/{} blocks ({:.0}%), height {}",
+                downloaded,
+                hashes.len(),
+                downloaded as f64 * 100.0 / hashes.len() as f64,
+                blockchain.block_height()
+            );
+        }
+    }
+    for worker in workers {
+        worker.abort();
+    }
+    result
+}
+
+/// Sync from `peer`: fetch its headers and, if its chain has more
+/// work than ours, the blocks from it and the `others` ahead of us
+async fn sync_from(
+    peer: &str,
+    mut connection: Connection,
+    best_height: u64,
+    latency: Duration,
+    others: Vec<(String, Connection, Duration)>,
+) -> Result<()> {
+    let (headers, work) = download_headers(peer, &mut connection, best_height).await?;
+    // a long chain of easy blocks is not worth downloading
+    if work <= BLOCKCHAIN.read().await.chain_work() {
+        return Err(anyhow!("its chain has no more work than ours"));
+    }
+    let mut peers = vec![(peer.to_string(), connection, latency)];
+    peers.extend(others);
+    download_blocks(&headers, peers).await
+}
+
+/// Height and work of our active chain
+async fn tip() -> (u64, U256) {
+    let blockchain = BLOCKCHAIN.read().await;
+    (blockchain.block_height(), blockchain.chain_work())
+}
+
+/// Catch up with the known nodes before serving anyone: repeatedly
+/// take the headers from the peer furthest ahead, the fastest one if
+/// several are, and the blocks from every peer ahead of us, dropping
+/// header peers that fail, until no remaining peer is ahead of us
+pub async fn initial_block_download() {
+    let mut peers: Vec<String> = PEERS.iter().map(|peer| peer.key().clone()).collect();
+    loop {
+        let height = BLOCKCHAIN.read().await.block_height();
+        let mut ahead: Vec<(String, Connection, u64, Duration)> = vec![];
+        let mut unreachable = vec![];
+        for peer in &peers {
+            match probe(peer).await {
+                Ok((connection, best_height, latency)) if best_height > height => {
+                    ahead.push((peer.clone(), connection, best_height, latency));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    println!("could not ask {} for blocks: {}", peer, e);
+                    unreachable.push(peer.clone());
+                }
+            }
+        }
+        peers.retain(|peer| !unreachable.contains(peer));
+
+        ahead.sort_by_key(|(_, _, best_height, latency)| (Reverse(*best_height), *latency));
+        let mut ahead = ahead.into_iter();
+        let Some((peer, connection, best_height, latency)) = ahead.next() else {
+            println!(
+                "initial block download done, height {}",
+                BLOCKCHAIN.read().await.block_height()
+            );
+            return;
+        };
+        let before = tip().await;
+        let others = ahead
+            .map(|(peer, connection, _, latency)| (peer, connection, latency))
+            .collect();
+        match sync_from(&peer, connection, best_height, latency, others).await {
+            // a peer claiming to be ahead without giving us anything
+            // would be asked again and again
+            Ok(()) if tip().await == before => {
+                println!(
+                    "syncing from {} made no progress, trying another peer",
+                    peer
+                );
+                peers.retain(|other| *other != peer);
+            }
+            Ok(()) => {}
+            Err(e) => {
+                println!("syncing from {} failed: {}, trying another peer", peer, e);
+                peers.retain(|other| *other != peer);
+            }
+        }
+    }
+}
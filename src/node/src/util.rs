@@ -0,0 +1,40 @@
+use btclib::util::Saveable;
+use tokio::time::{self, Duration};
+
+use crate::BLOCKCHAIN;
+
+/// how often the blockchain and the mempool are written to disk
+const SAVE_INTERVAL: Duration = Duration::from_secs(15);
+/// how often expired transactions are removed from the mempool
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Save the blockchain to `blockchain_path` and
+/// the mempool to `mempool_path`
+pub async fn save_all(blockchain_path: &str, mempool_path: &str) {
+    let blockchain = BLOCKCHAIN.read().await;
+    if let Err(e) = blockchain.save_to_file(blockchain_path) {
+        eprintln!("failed to save blockchain to {}: {}", blockchain_path, e);
+    }
+    if let Err(e) = blockchain.mempool().save_to_file(mempool_path) {
+        eprintln!("failed to save mempool to {}: {}", mempool_path, e);
+    }
+}
+
+/// Periodically save the blockchain and the mempool
+pub async fn save(blockchain_path: String, mempool_path: String) {
+    let mut interval = time::interval(SAVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        save_all(&blockchain_path, &mempool_path).await;
+    }
+}
+
+/// Periodically drop transactions that waited too long in the mempool
+pub async fn cleanup() {
+    let mut interval = time::interval(CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let mut blockchain = BLOCKCHAIN.write().await;
+        blockchain.cleanup_mempool();
+    }
+}
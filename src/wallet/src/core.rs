@@ -0,0 +1,188 @@
+use anyhow::{anyhow, Result};
+use btclib::crypto::{PrivateKey, PublicKey, Signature};
+use btclib::network::{Message, MessageCodec, Version};
+use btclib::types::{OutPoint, SigHash, Transaction, TransactionInput, TransactionOutput};
+use btclib::util::Saveable;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+type Connection = Framed<TcpStream, MessageCodec>;
+
+/// An unspent output owned by one of the wallet's keys
+#[derive(Clone, Debug)]
+pub struct Coin {
+    pub outpoint: OutPoint,
+    pub output: TransactionOutput,
+    /// reserved by a transaction waiting in the node's mempool
+    pub reserved: bool,
+    /// on the chain, otherwise created by a transaction
+    /// waiting in the node's mempool
+    pub confirmed: bool,
+    /// index of the key in `Core::keys` that can spend it
+    pub key: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Balance {
+    pub confirmed: u64,
+    pub unconfirmed: u64,
+    pub reserved: u64,
+}
+
+/// The node's next answer, its pings are answered on the way
+async fn receive(connection: &mut Connection) -> Result<Message> {
+    loop {
+        match connection
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("node closed the connection"))??
+        {
+            Message::Ping(nonce) => connection.send(Message::Pong(nonce)).await?,
+            message => return Ok(message),
+        }
+    }
+}
+
+pub struct Core {
+    pub keys: Vec<PrivateKey>,
+    connection: Connection,
+}
+
+impl Core {
+    pub fn load_keys(key_files: &[String]) -> Result<Vec<PrivateKey>> {
+        key_files
+            .iter()
+            .map(|file| {
+                PrivateKey::load_from_file(file)
+                    .map_err(|e| anyhow!("Error reading private key {}: {}", file, e))
+            })
+            .collect()
+    }
+
+    pub async fn connect(address: &str, keys: Vec<PrivateKey>) -> Result<Self> {
+        let mut stream = TcpStream::connect(address).await?;
+        let user_agent = format!("btclib-wallet/{}", env!("CARGO_PKG_VERSION"));
+        Version::new(0, user_agent, false)
+            .handshake(&mut stream)
+            .await?;
+        let connection = Framed::new(stream, MessageCodec::default());
+        Ok(Self { keys, connection })
+    }
+
+    /// Ask the node for the unspent outputs of every key
+    pub async fn fetch_coins(&mut self) -> Result<Vec<Coin>> {
+        let mut coins = vec![];
+        for (key, private_key) in self.keys.iter().enumerate() {
+            self.connection
+                .send(Message::FetchUTXOs(private_key.public_key()))
+                .await?;
+            match receive(&mut self.connection).await? {
+                Message::UTXOs(utxos) => coins.extend(utxos.into_iter().map(
+                    |(outpoint, output, reserved, confirmed)| Coin {
+                        outpoint,
+                        output,
+                        reserved,
+                        confirmed,
+                        key,
+                    },
+                )),
+                _ => return Err(anyhow!("Unexpected response to FetchUTXOs")),
+            }
+        }
+        Ok(coins)
+    }
+
+    pub async fn balance(&mut self) -> Result<Balance> {
+        let mut balance = Balance::default();
+        for coin in self.fetch_coins().await? {
+            if coin.reserved {
+                balance.reserved += coin.output.value;
+            } else if coin.confirmed {
+                balance.confirmed += coin.output.value;
+            } else {
+                balance.unconfirmed += coin.output.value;
+            }
+        }
+        Ok(balance)
+    }
+
+    /// Build a signed transaction paying `amount` to `recipient` and
+    /// `fee_rate` satoshis per byte to the miner, the change goes back
+    /// to the wallet's first key
+    pub async fn create_transaction(
+        &mut self,
+        recipient: &PublicKey,
+        amount: u64,
+        fee_rate: u64,
+    ) -> Result<Transaction> {
+        // what `inputs` coins have to cover: every input makes the
+        // transaction bigger, the size counts a change output
+        let total = |inputs: usize| {
+            let fee = fee_rate.checked_mul(Transaction::size_of(inputs, 2) as u64)?;
+            amount.checked_add(fee)
+        };
+
+        let mut selected = vec![];
+        let mut input_value = 0;
+        for coin in self.fetch_coins().await? {
+            if input_value >= total(selected.len()).ok_or_else(|| anyhow!("Amount overflows"))? {
+                break;
+            }
+            // coins spent by a pending transaction can not be used again
+            if coin.reserved {
+                continue;
+            }
+            input_value += coin.output.value;
+            selected.push(coin);
+        }
+        let total = total(selected.len()).ok_or_else(|| anyhow!("Amount overflows"))?;
+        if input_value < total {
+            return Err(anyhow!(
+                "Insufficient funds: have {}, need {}",
+                input_value,
+                total
+            ));
+        }
+
+        let mut outputs = vec![TransactionOutput {
+            value: amount,
+            pubkey: recipient.clone(),
+        }];
+        let change = input_value - total;
+        if change > 0 {
+            outputs.push(TransactionOutput {
+                value: change,
+                pubkey: self.keys[0].public_key(),
+            });
+        }
+
+        // sign every input over the whole transaction
+        let prev_outputs: Vec<OutPoint> = selected.iter().map(|coin| coin.outpoint).collect();
+        let inputs = selected
+            .iter()
+            .enumerate()
+            .map(|(index, coin)| {
+                let hash =
+                    Transaction::signature_hash(&prev_outputs, &outputs, index, SigHash::ALL)?;
+                Ok(TransactionInput {
+                    prev_output: prev_outputs[index],
+                    signature: Signature::sign(&hash, &self.keys[coin.key]),
+                    sighash: SigHash::ALL,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Transaction::new(inputs, outputs))
+    }
+
+    pub async fn submit_transaction(&mut self, transaction: Transaction) -> Result<()> {
+        self.connection
+            .send(Message::SubmitTransaction(transaction))
+            .await?;
+        match receive(&mut self.connection).await? {
+            Message::TransactionAccepted => Ok(()),
+            Message::TransactionRejected(e) => Err(anyhow!("Transaction rejected: {}", e)),
+            _ => Err(anyhow!("Unexpected response to SubmitTransaction")),
+        }
+    }
+}
@@ -1,3 +1,79 @@
-fn main() {
-    println!("Hello, world!");
+use anyhow::{anyhow, Result};
+use btclib::crypto::PublicKey;
+use btclib::util::Saveable;
+use clap::{Parser, Subcommand};
+use std::io;
+
+mod core;
+
+use crate::core::Core;
+
+#[derive(Parser)]
+#[command(about = "A btclib wallet")]
+struct Cli {
+    /// address of the node to talk to
+    #[arg(short, long, default_value = "127.0.0.1:9000")]
+    node: String,
+    /// private key files (as written by key_gen), the first one receives change
+    #[arg(short, long = "key", required = true)]
+    keys: Vec<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show confirmed, unconfirmed and reserved balance
+    Balance,
+    /// Send coins to the owner of a public key
+    Send {
+        /// public key file (PEM) of the recipient
+        recipient: String,
+        /// amount in satoshis
+        amount: u64,
+        /// fee in satoshis per byte paid to the miner
+        #[arg(short, long, default_value_t = btclib::MIN_RELAY_FEE_RATE)]
+        fee_rate: u64,
+    },
+    /// Print the public keys others can send coins to
+    Receive,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let keys = Core::load_keys(&cli.keys)?;
+
+    if let Command::Receive = cli.command {
+        for key in &keys {
+            key.public_key().save(io::stdout())?;
+        }
+        return Ok(());
+    }
+
+    let mut core = Core::connect(&cli.node, keys).await?;
+    match cli.command {
+        Command::Balance => {
+            let balance = core.balance().await?;
+            println!("confirmed:   {} sats", balance.confirmed);
+            println!("unconfirmed: {} sats", balance.unconfirmed);
+            println!("reserved:    {} sats", balance.reserved);
+        }
+        Command::Send {
+            recipient,
+            amount,
+            fee_rate,
+        } => {
+            let recipient = PublicKey::load_from_file(&recipient)
+                .map_err(|e| anyhow!("Error reading public key {}: {}", recipient, e))?;
+            let transaction = core
+                .create_transaction(&recipient, amount, fee_rate)
+                .await?;
+            println!("sending transaction {}", transaction.hash());
+            core.submit_transaction(transaction).await?;
+            println!("the node accepted it");
+        }
+        Command::Receive => unreachable!(),
+    }
+    Ok(())
 }